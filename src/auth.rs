@@ -0,0 +1,145 @@
+use std::future::Future;
+use std::time;
+use serde_json::Value;
+use log::debug;
+use crate::utils::EkError;
+
+/// Tokens are refreshed this long before they expire.
+const REFRESH_MARGIN: time::Duration = time::Duration::from_secs(60);
+
+/// Lifetime assumed when the server does not send `expires_in`.
+const DEFAULT_LIFETIME: time::Duration = time::Duration::from_secs(60 * 60);
+
+/// Access token together with the moment it stops being valid.
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub access_token: String,
+    pub expires_at: time::Instant,
+    /// Only sent by the Refinitiv Data Platform, used to get the next token without signing in
+    pub refresh_token: Option<String>,
+}
+
+impl Token {
+    /// Reads `access_token` and `expires_in` (seconds) from a handshake or OAuth2 token response.
+    pub fn from_response(res: &Value) -> Result<Token, EkError> {
+        let access_token = match res["access_token"].as_str() {
+            None => return Err(EkError::AuthError("Cannot get bearer access token".to_string())),
+            Some(r) => r.to_string()
+        };
+        // RDP sends expires_in as a string
+        let expires_in = match &res["expires_in"] {
+            Value::Number(n) => n.as_u64(),
+            Value::String(s) => s.parse::<u64>().ok(),
+            _ => None
+        };
+        let lifetime = match expires_in {
+            None => DEFAULT_LIFETIME,
+            Some(secs) => time::Duration::from_secs(secs)
+        };
+        Ok(Token {
+            access_token,
+            expires_at: time::Instant::now() + lifetime,
+            refresh_token: res["refresh_token"].as_str().map(|r| r.to_string()),
+        })
+    }
+
+    pub fn bearer(&self) -> String {
+        format!("Bearer {}", self.access_token)
+    }
+
+    fn fresh(&self, now: time::Instant) -> bool {
+        now + REFRESH_MARGIN < self.expires_at
+    }
+}
+
+/// Token shared by all concurrent requests of a connection.
+///
+/// Only one caller fetches a new token at a time, the others wait for it and reuse it.
+#[derive(Default)]
+pub struct TokenCache {
+    token: tokio::sync::Mutex<Option<Token>>,
+}
+
+impl TokenCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the cached token, fetching a new one with `fetch` when it is missing or about to expire.
+    ///
+    /// `fetch` is given the previous token, if any, so that it can be refreshed rather than replaced.
+    pub async fn get<F, Fut>(&self, fetch: F) -> Result<Token, EkError>
+        where F: FnOnce(Option<Token>) -> Fut,
+              Fut: Future<Output=Result<Token, EkError>>
+    {
+        let mut token = self.token.lock().await;
+        if let Some(t) = token.as_ref() {
+            if t.fresh(time::Instant::now()) {
+                return Ok(t.to_owned());
+            }
+            debug!("Access token is about to expire, refreshing");
+        }
+        let t = fetch(token.to_owned()).await?;
+        *token = Some(t.to_owned());
+        Ok(t)
+    }
+
+    pub async fn set(&self, token: Token) {
+        *self.token.lock().await = Some(token);
+    }
+
+    /// Marks the cached token as expired if it is still `access_token`, so that the next `get`
+    /// fetches a new one.
+    pub async fn invalidate(&self, access_token: &str) {
+        let mut token = self.token.lock().await;
+        if let Some(t) = token.as_mut() {
+            if t.access_token == access_token {
+                t.expires_at = time::Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_from_response() {
+        let token = Token::from_response(&json!({"access_token": "abc", "expires_in": 600})).unwrap();
+        assert_eq!(token.bearer(), "Bearer abc");
+        assert!(token.fresh(time::Instant::now()));
+        assert!(!token.fresh(time::Instant::now() + time::Duration::from_secs(550)));
+
+        let token = Token::from_response(&json!({"access_token": "abc", "expires_in": "300"})).unwrap();
+        assert!(token.expires_at <= time::Instant::now() + time::Duration::from_secs(300));
+
+        assert!(Token::from_response(&json!({"error": "invalid"})).is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let cache = TokenCache::new();
+        let fetches = AtomicUsize::new(0);
+        let fetch = |_| async {
+            let n = fetches.fetch_add(1, Ordering::SeqCst);
+            Token::from_response(&json!({"access_token": format!("token{}", n), "expires_in": 600}))
+        };
+
+        rt.block_on(async {
+            let (a, b) = tokio::join!(cache.get(fetch), cache.get(fetch));
+            assert_eq!(a.unwrap().access_token, "token0");
+            assert_eq!(b.unwrap().access_token, "token0");
+
+            cache.invalidate("other").await;
+            assert_eq!(cache.get(fetch).await.unwrap().access_token, "token0");
+
+            cache.invalidate("token0").await;
+            assert_eq!(cache.get(fetch).await.unwrap().access_token, "token1");
+        });
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+}
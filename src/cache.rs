@@ -0,0 +1,433 @@
+use std::collections::{btree_map, BTreeMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::prelude::*;
+use chrono::Duration;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::timeseries::{Interval, TsField};
+use crate::utils::{file_name, parse_timestamp, EkError};
+
+/// Window of time, both bounds included.
+pub type Range = (NaiveDateTime, NaiveDateTime);
+
+/// Local store of TimeSeries history, so that a request only downloads what was not downloaded before.
+///
+/// Every RIC, interval and field has its own file, `dir/daily/XOM/CLOSE.json`, holding the rows
+/// received for it and the windows they cover. The `*` field is stored as every column it returned.
+pub struct Cache {
+    dir: PathBuf,
+    refresh: bool,
+}
+
+/// What is held for one RIC, interval and field.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    /// Windows downloaded, sorted and not overlapping
+    ranges: Vec<Range>,
+    /// Columns of `rows` as the server described them, with their `name` and `type`
+    fields: Vec<Value>,
+    /// Rows in the order of their timestamps
+    rows: Vec<Vec<Value>>,
+}
+
+impl Cache {
+    /// Cache stored in `dir`, which is created on the first write.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            refresh: false,
+        }
+    }
+
+    /// Downloads every requested window again and replaces what is held for it, for history the
+    /// server has corrected since. Off by default.
+    pub fn set_refresh(&mut self, refresh: bool) {
+        self.refresh = refresh;
+    }
+
+    /// Parts of the window that are not held for the RIC and field, all of it when refreshing.
+    pub fn missing(&self, ric: &str, frq: Interval, field: &TsField, start_date: NaiveDateTime, end_date: NaiveDateTime) -> Result<Vec<Range>, EkError> {
+        if self.refresh {
+            return Ok(vec![(start_date, end_date)]);
+        }
+        let entry = read(&self.path(ric, frq, field))?;
+        Ok(entry.missing(start_date, end_date, frq.resolution()))
+    }
+
+    /// Stores the `timeseriesData` entries received for one request, reading and writing each
+    /// file once however many chunks the request was split into.
+    ///
+    /// # Arguments
+    ///
+    /// * `frq` - Interval of the request
+    /// * `fields` - Fields of the request
+    /// * `held_until` - Rows after it may still change, windows are only recorded as held up to it
+    /// * `entries` - The entry of each RIC as sent by the server, with the window it covers, in
+    ///   the order they were received. Its rows replace the ones held in the window
+    pub fn store(&self, frq: Interval, fields: &[TsField], held_until: NaiveDateTime, entries: &[(Range, Value)]) -> Result<(), EkError> {
+        let mut files: BTreeMap<PathBuf, Entry> = BTreeMap::new();
+        for (window, entry) in entries {
+            let ric = match entry["ric"].as_str() {
+                None => continue,
+                Some(r) => r
+            };
+            for field in fields {
+                let (columns, rows) = match select(entry, field) {
+                    None => {
+                        warn!("Response for {} has no TIMESTAMP, it is not cached", ric);
+                        break;
+                    }
+                    Some(r) => r
+                };
+                let held = match files.entry(self.path(ric, frq, field)) {
+                    btree_map::Entry::Occupied(o) => o.into_mut(),
+                    btree_map::Entry::Vacant(v) => {
+                        let held = read(v.key())?;
+                        v.insert(held)
+                    }
+                };
+                held.insert(*window, &columns, rows);
+                if window.0 <= held_until {
+                    held.hold((window.0, window.1.min(held_until)), frq.resolution());
+                }
+            }
+        }
+        for (path, held) in files.iter() {
+            write(path, held)?;
+        }
+        Ok(())
+    }
+
+    /// Reads what is held for the RIC in the window, as a `timeseriesData` entry.
+    ///
+    /// The fields are joined on their timestamps, rows sharing a timestamp being matched in order.
+    ///
+    /// # Returns
+    ///
+    /// The entry, or `None` when no row is held in the window
+    pub fn load(&self, ric: &str, frq: Interval, fields: &[TsField], start_date: NaiveDateTime, end_date: NaiveDateTime) -> Result<Option<Value>, EkError> {
+        let mut columns: Vec<Value> = vec![json!({"name": "TIMESTAMP", "type": "DateTime"})];
+        let mut rows: BTreeMap<(NaiveDateTime, usize), Vec<Value>> = BTreeMap::new();
+        for field in fields {
+            let held = read(&self.path(ric, frq, field))?;
+            let index = match held.timestamp_index() {
+                None => continue,
+                Some(i) => i
+            };
+            let position = held.fields.iter()
+                .map(|f| match columns.iter().position(|c| c["name"] == f["name"]) {
+                    Some(i) => i,
+                    None => {
+                        columns.push(f.to_owned());
+                        columns.len() - 1
+                    }
+                })
+                .collect::<Vec<usize>>();
+
+            let mut previous: Option<NaiveDateTime> = None;
+            let mut repeat = 0;
+            for row in held.rows.iter() {
+                let time = match row.get(index).and_then(|t| t.as_str()).and_then(parse_timestamp) {
+                    Some(t) if t >= start_date && t <= end_date => t,
+                    _ => continue
+                };
+                repeat = if previous == Some(time) { repeat + 1 } else { 0 };
+                previous = Some(time);
+                let values = rows.entry((time, repeat)).or_default();
+                for (value, i) in row.iter().zip(position.iter()) {
+                    if values.len() <= *i {
+                        values.resize(i + 1, Value::Null);
+                    }
+                    if !value.is_null() {
+                        values[*i] = value.to_owned();
+                    }
+                }
+            }
+        }
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let width = columns.len();
+        let data_points = rows.into_values()
+            .map(|mut r| {
+                r.resize(width, Value::Null);
+                r
+            })
+            .collect::<Vec<Vec<Value>>>();
+        Ok(Some(json!({
+            "ric": ric,
+            "statusCode": "Normal",
+            "fields": columns,
+            "dataPoints": data_points
+        })))
+    }
+
+    /// Forgets what is held for the RIC and interval.
+    ///
+    /// # Arguments
+    ///
+    /// * `ric` - RIC to forget
+    /// * `frq` - Interval to forget
+    /// * `window` - Only forget the rows in this window, everything when `None`
+    pub fn invalidate(&self, ric: &str, frq: Interval, window: Option<Range>) -> Result<(), EkError> {
+        let dir = self.dir.join(frq.to_string()).join(file_name(ric));
+        if !dir.exists() {
+            return Ok(());
+        }
+        match window {
+            None => fs::remove_dir_all(&dir)?,
+            Some(w) => {
+                for file in fs::read_dir(&dir)? {
+                    let path = file?.path();
+                    let mut held = read(&path)?;
+                    held.remove(w, frq.resolution());
+                    write(&path, &held)?;
+                }
+            }
+        }
+        debug!("Invalidated {} {} {:?}", ric, frq, window);
+        Ok(())
+    }
+
+    fn path(&self, ric: &str, frq: Interval, field: &TsField) -> PathBuf {
+        self.dir
+            .join(frq.to_string())
+            .join(file_name(ric))
+            .join(format!("{}.json", file_name(field.as_str())))
+    }
+}
+
+impl Entry {
+    /// Parts of the window outside of the held ranges.
+    fn missing(&self, start_date: NaiveDateTime, end_date: NaiveDateTime, resolution: Duration) -> Vec<Range> {
+        let mut res = Vec::new();
+        let mut from = start_date;
+        for (s, e) in self.ranges.iter() {
+            if *e < from {
+                continue;
+            }
+            if *s > end_date {
+                break;
+            }
+            if *s > from {
+                res.push((from, *s - resolution));
+            }
+            from = *e + resolution;
+        }
+        if from <= end_date {
+            res.push((from, end_date));
+        }
+        res
+    }
+
+    /// Records a window as held, merging it with the ranges it overlaps or touches.
+    fn hold(&mut self, window: Range, resolution: Duration) {
+        self.ranges.push(window);
+        self.ranges.sort();
+        let mut merged: Vec<Range> = Vec::with_capacity(self.ranges.len());
+        for (s, e) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 + resolution => last.1 = last.1.max(e),
+                _ => merged.push((s, e))
+            }
+        }
+        self.ranges = merged;
+    }
+
+    /// Replaces the rows of the window with `rows`, adding the columns that are not held yet.
+    fn insert(&mut self, window: Range, columns: &[Value], rows: Vec<Vec<Value>>) {
+        let position = columns.iter()
+            .map(|f| match self.fields.iter().position(|c| c["name"] == f["name"]) {
+                Some(i) => i,
+                None => {
+                    self.fields.push(f.to_owned());
+                    self.fields.len() - 1
+                }
+            })
+            .collect::<Vec<usize>>();
+        let width = self.fields.len();
+
+        self.retain_outside(window);
+        for row in self.rows.iter_mut() {
+            row.resize(width, Value::Null);
+        }
+        for row in rows {
+            let mut values = vec![Value::Null; width];
+            for (value, i) in row.into_iter().zip(position.iter()) {
+                values[*i] = value;
+            }
+            self.rows.push(values);
+        }
+        // Stable, rows sharing a timestamp keep the order the server sent them in
+        if let Some(index) = self.timestamp_index() {
+            self.rows.sort_by_key(|r| r.get(index).and_then(|t| t.as_str()).and_then(parse_timestamp));
+        }
+    }
+
+    /// Drops the rows of the window and cuts it out of the held ranges.
+    fn remove(&mut self, window: Range, resolution: Duration) {
+        self.retain_outside(window);
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+        for (s, e) in self.ranges.drain(..) {
+            if e < window.0 || s > window.1 {
+                ranges.push((s, e));
+                continue;
+            }
+            if s < window.0 {
+                ranges.push((s, window.0 - resolution));
+            }
+            if e > window.1 {
+                ranges.push((window.1 + resolution, e));
+            }
+        }
+        self.ranges = ranges;
+    }
+
+    fn retain_outside(&mut self, window: Range) {
+        if let Some(index) = self.timestamp_index() {
+            self.rows.retain(|r| match r.get(index).and_then(|t| t.as_str()).and_then(parse_timestamp) {
+                Some(t) => t < window.0 || t > window.1,
+                None => true
+            });
+        }
+    }
+
+    fn timestamp_index(&self) -> Option<usize> {
+        self.fields.iter().position(|f| f["name"] == "TIMESTAMP")
+    }
+}
+
+/// Columns of a `timeseriesData` entry stored for `field`: `TIMESTAMP` and the field, or every
+/// column for `*`.
+///
+/// # Returns
+///
+/// The columns and the rows, or `None` when the entry has no `TIMESTAMP`
+fn select(entry: &Value, field: &TsField) -> Option<(Vec<Value>, Vec<Vec<Value>>)> {
+    let fields = entry["fields"].as_array()?;
+    let name = |f: &Value| f["name"].as_str().unwrap_or_default().to_owned();
+    let timestamp = fields.iter().position(|f| name(f) == "TIMESTAMP")?;
+    let indexes = match field {
+        TsField::All => (0..fields.len()).collect::<Vec<usize>>(),
+        f => {
+            let mut i = vec![timestamp];
+            i.extend(fields.iter().position(|c| *f != TsField::Timestamp && name(c).eq_ignore_ascii_case(f.as_str())));
+            i
+        }
+    };
+    let columns = indexes.iter().map(|i| fields[*i].to_owned()).collect();
+    let rows = entry["dataPoints"].as_array()
+        .map(|d| d.iter()
+            .map(|row| indexes.iter().map(|i| row[*i].to_owned()).collect::<Vec<Value>>())
+            .collect())
+        .unwrap_or_default();
+    Some((columns, rows))
+}
+
+fn read(path: &Path) -> Result<Entry, EkError> {
+    if !path.exists() {
+        return Ok(Entry::default());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// Writes the entry to a temporary file first, so that an interrupted write leaves the old one.
+fn write(path: &Path, entry: &Entry) -> Result<(), EkError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(entry)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(d: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 1, d).unwrap().and_hms_opt(0, 0, 0).unwrap()
+    }
+
+    fn response(ric: &str, days: &[u32]) -> Value {
+        let rows = days.iter()
+            .map(|d| json!([format!("2023-01-{:02}T00:00:00Z", d), *d as f64, *d as i64 * 10]))
+            .collect::<Vec<Value>>();
+        json!({
+            "ric": ric,
+            "statusCode": "Normal",
+            "fields": [{"name": "TIMESTAMP", "type": "DateTime"}, {"name": "CLOSE", "type": "Double"}, {"name": "VOLUME", "type": "Long"}],
+            "dataPoints": rows
+        })
+    }
+
+    #[test]
+    fn test_ranges() {
+        let second = Duration::seconds(1);
+        let mut entry = Entry::default();
+        assert_eq!(entry.missing(day(1), day(31), second), vec![(day(1), day(31))]);
+
+        entry.hold((day(5), day(10)), second);
+        entry.hold((day(20), day(25)), second);
+        entry.hold((day(10) + second, day(12)), second);
+        assert_eq!(entry.ranges, vec![(day(5), day(12)), (day(20), day(25))]);
+        assert_eq!(entry.missing(day(1), day(31), second), vec![
+            (day(1), day(5) - second),
+            (day(12) + second, day(20) - second),
+            (day(25) + second, day(31)),
+        ]);
+        assert!(entry.missing(day(6), day(11), second).is_empty());
+        assert_eq!(entry.missing(day(8), day(22), second), vec![(day(12) + second, day(20) - second)]);
+
+        entry.remove((day(7), day(21)), second);
+        assert_eq!(entry.ranges, vec![(day(5), day(7) - second), (day(21) + second, day(25))]);
+    }
+
+    #[test]
+    fn test_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut cache = Cache::new(dir);
+        let fields = vec![TsField::Close, TsField::Volume];
+
+        cache.store(Interval::Daily, &fields, day(5), &[((day(2), day(6)), response("EUR/USD", &[2, 3, 4, 5, 6]))]).unwrap();
+        assert!(dir.join("daily").join("EUR%2FUSD").join("CLOSE.json").exists());
+        // The last row may still change
+        assert_eq!(cache.missing("EUR/USD", Interval::Daily, &TsField::Close, day(2), day(9)).unwrap(), vec![(day(5) + Duration::seconds(1), day(9))]);
+        assert_eq!(cache.missing("EUR/USD", Interval::Daily, &TsField::Open, day(2), day(3)).unwrap(), vec![(day(2), day(3))]);
+
+        let mut corrected = response("EUR/USD", &[6]);
+        corrected["dataPoints"][0][1] = json!(60.5);
+        // Both chunks of a request land in the same file
+        let chunks = [
+            ((day(5) + Duration::seconds(1), day(6)), corrected),
+            ((day(6) + Duration::seconds(1), day(9)), response("EUR/USD", &[9])),
+        ];
+        cache.store(Interval::Daily, &[TsField::Close], day(9), &chunks).unwrap();
+
+        let entry = cache.load("EUR/USD", Interval::Daily, &fields, day(3), day(9)).unwrap().unwrap();
+        assert_eq!(entry["fields"].as_array().unwrap().iter().map(|f| f["name"].as_str().unwrap()).collect::<Vec<&str>>(), vec!["TIMESTAMP", "CLOSE", "VOLUME"]);
+        assert_eq!(entry["dataPoints"], json!([
+            ["2023-01-03T00:00:00Z", 3.0, 30],
+            ["2023-01-04T00:00:00Z", 4.0, 40],
+            ["2023-01-05T00:00:00Z", 5.0, 50],
+            ["2023-01-06T00:00:00Z", 60.5, 60],
+            ["2023-01-09T00:00:00Z", 9.0, null],
+        ]));
+        assert!(cache.missing("EUR/USD", Interval::Daily, &TsField::Close, day(2), day(9)).unwrap().is_empty());
+
+        cache.set_refresh(true);
+        assert_eq!(cache.missing("EUR/USD", Interval::Daily, &TsField::Close, day(2), day(9)).unwrap(), vec![(day(2), day(9))]);
+        cache.set_refresh(false);
+
+        cache.invalidate("EUR/USD", Interval::Daily, Some((day(4), day(5)))).unwrap();
+        let entry = cache.load("EUR/USD", Interval::Daily, &[TsField::Close], day(1), day(31)).unwrap().unwrap();
+        assert_eq!(entry["dataPoints"].as_array().unwrap().len(), 4);
+        assert_eq!(cache.missing("EUR/USD", Interval::Daily, &TsField::Volume, day(2), day(9)).unwrap(), vec![(day(4), day(9))]);
+        cache.invalidate("EUR/USD", Interval::Daily, None).unwrap();
+        assert!(cache.load("EUR/USD", Interval::Daily, &fields, day(1), day(31)).unwrap().is_none());
+    }
+}
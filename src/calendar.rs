@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+
+/// Days and hours a market trades, used to size TimeSeries chunks.
+pub trait Calendar: Send + Sync {
+    fn is_trading_day(&self, day: NaiveDate) -> bool;
+
+    /// Longest time the market trades on one day, intraday bars are only counted within it.
+    fn session(&self) -> Duration {
+        Duration::hours(24)
+    }
+
+    /// Number of trading days from `start` to `end`, both included.
+    fn trading_days(&self, start: NaiveDate, end: NaiveDate) -> usize {
+        start.iter_days()
+            .take_while(|d| *d <= end)
+            .filter(|d| self.is_trading_day(*d))
+            .count()
+    }
+}
+
+fn is_weekday(day: NaiveDate) -> bool {
+    !matches!(day.weekday(), Weekday::Sat | Weekday::Sun)
+}
+
+/// Monday to Friday, around the clock, which suits FX and rates.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Weekdays;
+
+impl Calendar for Weekdays {
+    fn is_trading_day(&self, day: NaiveDate) -> bool {
+        is_weekday(day)
+    }
+}
+
+/// Weekdays minus a fixed list of exchange holidays.
+#[derive(Clone, Debug)]
+pub struct Holidays {
+    holidays: HashSet<NaiveDate>,
+    session: Duration,
+}
+
+impl Holidays {
+    /// # Arguments
+    ///
+    /// * `holidays` - Days the exchange is closed on
+    /// * `session` - Longest trading session of a day, extended hours included
+    pub fn new(holidays: impl IntoIterator<Item=NaiveDate>, session: Duration) -> Self {
+        Self {
+            holidays: holidays.into_iter().collect(),
+            session,
+        }
+    }
+}
+
+impl Calendar for Holidays {
+    fn is_trading_day(&self, day: NaiveDate) -> bool {
+        is_weekday(day) && !self.holidays.contains(&day)
+    }
+
+    fn session(&self) -> Duration {
+        self.session
+    }
+}
+
+/// New York Stock Exchange, with its holidays computed from the exchange rules.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Nyse;
+
+impl Nyse {
+    fn is_holiday(day: NaiveDate) -> bool {
+        let year = day.year();
+        let fixed = |month, d| NaiveDate::from_ymd_opt(year, month, d).map(observed);
+        let mut holidays = vec![
+            nth_weekday(year, 1, Weekday::Mon, 3),
+            nth_weekday(year, 2, Weekday::Mon, 3),
+            easter(year).and_then(|e| e.checked_sub_signed(Duration::days(2))),
+            last_weekday(year, 5, Weekday::Mon),
+            fixed(7, 4),
+            nth_weekday(year, 9, Weekday::Mon, 1),
+            nth_weekday(year, 11, Weekday::Thu, 4),
+            fixed(12, 25),
+        ];
+        // A Saturday New Year is not moved to the Friday before
+        holidays.push(NaiveDate::from_ymd_opt(year, 1, 1)
+            .map(|d| if d.weekday() == Weekday::Sun { d.succ_opt().unwrap_or(d) } else { d }));
+        if year >= 2022 {
+            holidays.push(fixed(6, 19));
+        }
+        holidays.into_iter().flatten().any(|h| h == day)
+    }
+}
+
+impl Calendar for Nyse {
+    fn is_trading_day(&self, day: NaiveDate) -> bool {
+        is_weekday(day) && !Nyse::is_holiday(day)
+    }
+
+    /// Pre-market to after-hours, 04:00 to 20:00 New York time.
+    fn session(&self) -> Duration {
+        Duration::hours(16)
+    }
+}
+
+/// Saturday holidays are observed on the Friday before, Sunday ones on the Monday after.
+fn observed(day: NaiveDate) -> NaiveDate {
+    match day.weekday() {
+        Weekday::Sat => day.pred_opt().unwrap_or(day),
+        Weekday::Sun => day.succ_opt().unwrap_or(day),
+        _ => day
+    }
+}
+
+fn nth_weekday(year: i32, month: u32, weekday: Weekday, n: u8) -> Option<NaiveDate> {
+    NaiveDate::from_weekday_of_month_opt(year, month, weekday, n)
+}
+
+fn last_weekday(year: i32, month: u32, weekday: Weekday) -> Option<NaiveDate> {
+    nth_weekday(year, month, weekday, 5).or_else(|| nth_weekday(year, month, weekday, 4))
+}
+
+/// Easter Sunday of the Gregorian calendar (anonymous Gregorian algorithm).
+fn easter(year: i32) -> Option<NaiveDate> {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn test_nyse() {
+        assert_eq!(easter(2023), Some(date(2023, 4, 9)));
+        // Good Friday, Juneteenth, Independence Day observed on Monday, Thanksgiving
+        for d in [date(2023, 4, 7), date(2023, 6, 19), date(2021, 7, 5), date(2023, 11, 23), date(2023, 1, 2)] {
+            assert!(!Nyse.is_trading_day(d), "{} should be a holiday", d);
+        }
+        // Saturday New Year is not observed on the Friday before
+        assert!(Nyse.is_trading_day(date(2021, 12, 31)));
+        assert!(Nyse.is_trading_day(date(2021, 6, 18)));
+        // 2023 had 250 trading days
+        assert_eq!(Nyse.trading_days(date(2023, 1, 1), date(2023, 12, 31)), 250);
+    }
+
+    #[test]
+    fn test_holidays() {
+        let calendar = Holidays::new(vec![date(2023, 5, 1)], Duration::hours(9));
+        assert!(!calendar.is_trading_day(date(2023, 5, 1)));
+        assert!(!calendar.is_trading_day(date(2023, 5, 6)));
+        assert_eq!(calendar.trading_days(date(2023, 5, 1), date(2023, 5, 7)), 4);
+        assert_eq!(Weekdays.trading_days(date(2023, 5, 1), date(2023, 5, 7)), 5);
+    }
+}
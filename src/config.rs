@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::connection::Connection;
+use crate::platform::PlatformConfig;
+use crate::utils::EkError;
+
+/// Environment variable holding the app key, it takes precedence over the configuration file.
+pub const APP_KEY_VAR: &str = "EIKON_APP_KEY";
+
+/// Environment variable pointing to the configuration file.
+pub const CONFIG_VAR: &str = "EIKON_DL_CONFIG";
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+
+/// Settings of the command line tools, read from a TOML file such as
+///
+/// ```toml
+/// app_key = "..."
+/// host = "127.0.0.1"
+/// # Found through the Eikon proxy files when not set
+/// port = 9000
+/// # Requests sent today, shared by every run, usage.json next to the default configuration file
+/// # when not set
+/// usage_file = "/var/lib/eikon-dl/usage.json"
+///
+/// # Connects to the Refinitiv Data Platform instead of the desktop proxy
+/// [platform]
+/// username = "..."
+/// password = "..."
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    pub app_key: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub usage_file: Option<PathBuf>,
+    pub platform: Option<PlatformSettings>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlatformSettings {
+    pub username: String,
+    pub password: String,
+    pub base_url: Option<String>,
+}
+
+impl Config {
+    /// Reads the configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - File to read, it must exist. Without it the file named by `EIKON_DL_CONFIG` is
+    ///   read, then `eikon-dl/config.toml` in the user configuration directory if there is one.
+    pub fn load(path: Option<&Path>) -> Result<Config, EkError> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None => match std::env::var_os(CONFIG_VAR).map(PathBuf::from).or_else(default_path) {
+                Some(p) if p.exists() => p,
+                _ => return Ok(Config::default())
+            }
+        };
+        Config::parse(&fs::read_to_string(&path)?)
+    }
+
+    pub fn parse(s: &str) -> Result<Config, EkError> {
+        toml::from_str(s).map_err(|e| EkError::Config(e.to_string()))
+    }
+
+    /// App key from `EIKON_APP_KEY`, or from the file when the variable is not set.
+    pub fn app_key(&self) -> Result<String, EkError> {
+        match std::env::var(APP_KEY_VAR) {
+            Ok(k) if !k.trim().is_empty() => Ok(k.trim().to_string()),
+            _ => match &self.app_key {
+                Some(k) => Ok(k.to_owned()),
+                None => Err(EkError::Config(format!("No app key, set {} or app_key in the configuration file", APP_KEY_VAR)))
+            }
+        }
+    }
+
+    /// Opens the connection the configuration describes, discovering the proxy port when it is not set.
+    ///
+    /// The daily request count is kept in the usage file, so that every run shares one quota.
+    pub fn connect(&self) -> Result<Connection, EkError> {
+        let connection = self.open()?;
+        if let Some(path) = self.usage_file.to_owned().or_else(|| Some(default_dir()?.join("usage.json"))) {
+            connection.set_usage_file(&path)?;
+        }
+        Ok(connection)
+    }
+
+    fn open(&self) -> Result<Connection, EkError> {
+        let app_key = self.app_key()?;
+        if let Some(p) = &self.platform {
+            let mut config = PlatformConfig::new(app_key, p.username.to_owned(), p.password.to_owned());
+            if let Some(url) = &p.base_url {
+                config.base_url = url.trim_end_matches('/').to_string();
+            }
+            return Ok(Connection::platform(config));
+        }
+        let host = self.host.to_owned().unwrap_or(DEFAULT_HOST.to_string());
+        match self.port {
+            Some(port) => Ok(Connection::new(app_key, host, port)),
+            None => Connection::discover(app_key, host)
+        }
+    }
+}
+
+/// `eikon-dl` in the user configuration directory.
+fn default_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("APPDATA") {
+        Some(d) => PathBuf::from(d),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config")
+    };
+    Some(dir.join("eikon-dl"))
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(default_dir()?.join("config.toml"))
+}
+
+/// Reads a list of instruments or fields, one per line, skipping blank lines and `#` comments.
+pub fn read_list(path: &Path) -> Result<Vec<String>, EkError> {
+    let content = fs::read_to_string(path)?;
+    Ok(parse_list(&content))
+}
+
+fn parse_list(content: &str) -> Vec<String> {
+    content.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect()
+}
+
+/// Parses `KEY=VALUE` pairs, e.g. `SDate=2020-01-01`.
+pub fn parse_params(params: &[String]) -> Result<HashMap<String, String>, EkError> {
+    let mut res = HashMap::with_capacity(params.len());
+    for p in params {
+        match p.split_once('=') {
+            None => return Err(EkError::Config(format!("Parameter {} is not KEY=VALUE", p))),
+            Some((k, v)) => res.insert(k.trim().to_string(), v.trim().to_string()),
+        };
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = Config::parse("app_key = \"abc\"\nport = 9060\nusage_file = \"usage.json\"\n").unwrap();
+        assert_eq!(config.app_key, Some("abc".to_string()));
+        assert_eq!(config.port, Some(9060));
+        assert_eq!(config.usage_file, Some(PathBuf::from("usage.json")));
+        assert!(config.platform.is_none());
+
+        let config = Config::parse("[platform]\nusername = \"me\"\npassword = \"secret\"\n").unwrap();
+        assert_eq!(config.platform.unwrap().username, "me");
+        assert!(matches!(Config::parse("port = \"x\""), Err(EkError::Config(_))));
+    }
+
+    #[test]
+    fn test_lists() {
+        let list = parse_list("# Oil majors\nXOM\n\nTR.CLOSE(Curn=EUR,Scale=6)\n  BP.L  \n");
+        assert_eq!(list, vec!["XOM", "TR.CLOSE(Curn=EUR,Scale=6)", "BP.L"]);
+
+        let params = parse_params(&["SDate=2020-01-01".to_string(), "Frq = D".to_string()]).unwrap();
+        assert_eq!(params["SDate"], "2020-01-01");
+        assert_eq!(params["Frq"], "D");
+        assert!(parse_params(&["SDate".to_string()]).is_err());
+    }
+}
@@ -1,12 +1,30 @@
 use serde_json::{json, Value};
-use std::{fmt, thread, time};
-use std::future::Future;
-use tokio::runtime::Runtime;
+use std::{env, fmt, fs, time};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::{Handle, Runtime};
 use tokio::task::{JoinHandle};
-use crate::utils::{EkError};
-use log::{info, error, debug, trace, warn};
+use crate::auth::{Token, TokenCache};
+use crate::platform;
+use crate::platform::PlatformConfig;
+use crate::rate_limit::{Limits, RateLimiter};
+use crate::retry::RetryPolicy;
+use crate::utils::{clean_string, EkError};
+use log::{debug, info, warn};
 
-#[derive(Copy, Clone)]
+/// Time a Datagrid ticket is polled for before it is considered expired.
+const DEFAULT_TICKET_TIMEOUT: time::Duration = time::Duration::from_secs(120);
+
+/// Wait used between ticket polls when the server does not suggest one.
+const DEFAULT_TICKET_WAIT: time::Duration = time::Duration::from_millis(1000);
+
+/// Time allowed for a port to answer `/api/status` during discovery.
+const STATUS_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// Ports the Eikon / Workspace API proxy is known to listen on, in the order they are probed.
+const DEFAULT_PORTS: [u16; 13] = [9000, 9001, 9002, 9003, 9004, 9005, 9006, 9007, 9008, 9009, 9010, 9060, 36036];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Direction {
     Datagrid,
     TimeSeries,
@@ -21,21 +39,147 @@ impl fmt::Display for Direction {
     }
 }
 
+/// Service the requests of a `Connection` are sent to.
+#[derive(Clone, Debug)]
+pub enum Backend {
+    /// Eikon / Workspace API proxy running on the desktop
+    Desktop,
+    /// Refinitiv Data Platform, reached without a desktop application
+    Platform(PlatformConfig),
+}
+
+/// Settings shared by the requests sent during one call, handed to each spawned request.
+pub struct RequestContext {
+    backend: Backend,
+    client: reqwest::Client,
+    address: String,
+    app_key: String,
+    tokens: Arc<TokenCache>,
+    ticket_timeout: time::Duration,
+    retry: RetryPolicy,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RequestContext {
+    /// Cached access token, a new one is fetched when there is none or it is about to expire.
+    async fn token(&self) -> Result<Token, EkError> {
+        self.tokens.get(|previous| async move {
+            match &self.backend {
+                Backend::Desktop => {
+                    let hk = self.retry.run("Handshake", || Connection::handshake_request(
+                        &self.client,
+                        &self.address,
+                        &self.app_key,
+                    )).await?;
+                    Token::from_response(&hk)
+                }
+                Backend::Platform(config) => {
+                    self.retry.run("Sign in", || platform::fetch_token(
+                        &self.client,
+                        config,
+                        previous.to_owned(),
+                    )).await
+                }
+            }
+        }).await
+    }
+
+    /// Sends `body` to the proxy with the cached access token.
+    async fn authorized_request(&self, body: &Value, direction: Direction) -> Result<Value, EkError> {
+        self.authorized(direction, |bearer| Connection::req_client(
+            &self.client,
+            body,
+            &self.address,
+            &self.app_key,
+            Some(bearer),
+        )).await
+    }
+
+    /// Sends the request made by `build` from the bearer header value, refreshing the token once
+    /// if the server rejects it.
+    async fn authorized<F>(&self, direction: Direction, build: F) -> Result<Value, EkError>
+        where F: Fn(&str) -> reqwest::RequestBuilder
+    {
+        let token = self.token().await?;
+        match self.send_with_token(direction, &build, &token).await {
+            Err(EkError::ServerError(401, _)) => {
+                info!("Access token rejected, refreshing");
+                self.tokens.invalidate(&token.access_token).await;
+                let token = self.token().await?;
+                self.send_with_token(direction, &build, &token).await
+            }
+            r => r
+        }
+    }
+
+    async fn send_with_token<F>(&self, direction: Direction, build: &F, token: &Token) -> Result<Value, EkError>
+        where F: Fn(&str) -> reqwest::RequestBuilder
+    {
+        let req = build(&token.bearer());
+        let json_res = Connection::request_executioner(req, &self.limiter, direction).await?;
+        if json_res.get("ErrorCode").is_some() {
+            return Err(server_error(&json_res));
+        }
+        Ok(json_res)
+    }
+}
+
+/// Connection to the Eikon proxy.
+///
+/// A single pooled HTTP client is shared by every request made through the connection, and the
+/// runtime used by the blocking methods is created on first use and kept for later calls.
+///
+/// Clones share the rate limiter, token cache, HTTP client and runtime, so that every
+/// `TimeSeries` and `Datagrid` made from one connection stays within the same budget.
+#[derive(Clone)]
 pub struct Connection {
+    backend: Backend,
     app_key: String,
     url: String,
-    port: i16,
+    port: u16,
+    ticket_timeout: time::Duration,
+    retry: RetryPolicy,
+    limiter: Arc<RateLimiter>,
+    tokens: Arc<TokenCache>,
+    client: reqwest::Client,
+    runtime: Arc<OnceLock<Runtime>>,
 }
 
 impl Connection {
-    pub fn new(app_key: String, ip: String, port: i16) -> Self {
+    pub fn new(app_key: String, ip: String, port: u16) -> Self {
+        let client = reqwest::Client::builder()
+            .pool_idle_timeout(time::Duration::from_secs(90))
+            .tcp_keepalive(time::Duration::from_secs(60))
+            .build()
+            .unwrap_or_default();
         Self {
+            backend: Backend::Desktop,
             app_key: app_key.to_owned(),
             url: ip.to_owned(),
             port,
+            ticket_timeout: DEFAULT_TICKET_TIMEOUT,
+            retry: RetryPolicy::default(),
+            limiter: Arc::new(RateLimiter::new()),
+            tokens: Arc::new(TokenCache::new()),
+            client,
+            runtime: Arc::new(OnceLock::new()),
         }
     }
 
+    /// Creates a connection to the Refinitiv Data Platform instead of the desktop proxy.
+    pub fn platform(config: PlatformConfig) -> Self {
+        let mut conn = Connection::new(config.client_id.to_owned(), String::new(), 0);
+        conn.backend = Backend::Platform(config);
+        conn
+    }
+
+    /// Creates a connection and configures it on the first port where the proxy reports ready.
+    pub fn discover(app_key: String, ip: String) -> Result<Self, EkError> {
+        let mut conn = Connection::new(app_key, ip, DEFAULT_PORTS[0]);
+        conn.discover_port()?;
+        Ok(conn)
+    }
+
     fn get_url(&self) -> String {
         format!("http://{}", self.url)
     }
@@ -46,30 +190,162 @@ impl Connection {
         &self.app_key
     }
 
-    pub fn set_port(&mut self, port: i16) { self.port = port }
+    pub fn get_port(&self) -> u16 { self.port }
+
+    pub fn backend(&self) -> &Backend { &self.backend }
+
+    pub fn set_port(&mut self, port: u16) { self.port = port }
+
+    /// Sets how long a Datagrid ticket is polled before giving up with `EkError::TicketExpired`.
+    pub fn set_ticket_timeout(&mut self, timeout: time::Duration) { self.ticket_timeout = timeout }
+
+    /// Sets the retry policy applied to the handshake and to every Datagrid and TimeSeries request.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) { self.retry = retry }
+
+    /// Sets the rate limits applied to requests in `direction`, resetting its budget.
+    pub fn set_rate_limits(&self, direction: Direction, limits: &Limits) {
+        self.limiter.set_limits(direction, limits)
+    }
+
+    /// Shares the daily request count with other runs through `path`, see `RateLimiter::set_usage_file`.
+    pub fn set_usage_file(&self, path: &Path) -> Result<(), EkError> {
+        self.limiter.set_usage_file(path)
+    }
+
+    /// Number of requests left in today's quota for `direction`, as counted on this side: by this
+    /// connection, or by every run sharing its usage file.
+    pub fn remaining_daily_requests(&self, direction: Direction) -> u32 {
+        self.limiter.remaining_daily(direction)
+    }
+
+    /// Runtime driving the blocking methods, built the first time it is needed.
+    ///
+    /// Blocking on it from inside another runtime would panic, so that case is reported as an
+    /// error pointing to the async methods instead.
+    pub(crate) fn runtime(&self) -> Result<&Runtime, EkError> {
+        if Handle::try_current().is_ok() {
+            return Err(EkError::ThreadError("Blocking call made from within an async runtime, use the async methods instead".to_string()));
+        }
+        if let Some(rt) = self.runtime.get() {
+            return Ok(rt);
+        }
+        let rt = match tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build() {
+            Ok(r) => r,
+            Err(e) => return Err(EkError::ThreadError(e.to_string()))
+        };
+        Ok(self.runtime.get_or_init(|| rt))
+    }
+
+    pub fn status(&self, port: &u16) -> Result<Value, EkError> {
+        self.runtime()?.block_on(self.status_async(port))
+    }
 
-    pub fn status(&self, port: &i16) -> reqwest::Result<reqwest::blocking::Response> {
+    pub async fn status_async(&self, port: &u16) -> Result<Value, EkError> {
         let address = format!("{}:{}/api/status", self.get_url(), port);
-        let client = reqwest::blocking::Client::new();
-        client.get(address)
-            .header("X-tr-applicationid", self.get_app_key())
-            .send()
+        let req = self.client.get(address)
+            .timeout(STATUS_TIMEOUT)
+            .header("X-tr-applicationid", self.get_app_key());
+        match req.send().await {
+            Err(e) => Err(e.into()),
+            Ok(r) => {
+                match r.json::<Value>().await {
+                    Err(e) => Err(e.into()),
+                    Ok(r) => Ok(r)
+                }
+            }
+        }
+    }
+
+    /// Checks that the backend can be reached with the credentials of the connection.
+    ///
+    /// # Returns
+    ///
+    /// The proxy status for the desktop, or the token type and lifetime for the platform
+    pub fn check(&self) -> Result<Value, EkError> {
+        match &self.backend {
+            Backend::Desktop => self.status(&self.port),
+            Backend::Platform(config) => {
+                let res = self.runtime()?.block_on(platform::sign_in(&self.client, config))?;
+                Ok(json!({"base_url": config.base_url, "token_type": res["token_type"], "expires_in": res["expires_in"]}))
+            }
+        }
+    }
+
+    /// Checks whether the proxy on `port` answers `/api/status` with `ST_PROXY_READY`.
+    fn proxy_ready(&self, port: &u16) -> bool {
+        match self.status(port) {
+            Err(e) => {
+                debug!("Port {}: {}", port, e);
+                false
+            }
+            Ok(status) => {
+                debug!("Port {}: {}", port, status);
+                status["statusCode"] == "ST_PROXY_READY"
+            }
+        }
+    }
+
+    /// Probes the ports found in Eikon's `.portInUse` files followed by the known default ports,
+    /// and sets the connection to the first one where the proxy is ready.
+    ///
+    /// # Returns
+    ///
+    /// The port that was selected, or `EkError::PortDiscovery` listing every port tried
+    pub fn discover_port(&mut self) -> Result<u16, EkError> {
+        let ports = candidate_ports(&port_in_use_files());
+        for port in ports.iter() {
+            if self.proxy_ready(port) {
+                info!("Eikon proxy found on port {}", port);
+                self.set_port(*port);
+                return Ok(*port);
+            }
+        }
+        Err(EkError::PortDiscovery(ports))
     }
 
     pub fn handshake(&self) -> Result<Value, EkError> {
-        let address = format!("{}/api/handshake", self.get_address());
-        let app_key = self.get_app_key();
+        self.runtime()?.block_on(self.handshake_async())
+    }
+
+    /// Performs a new handshake, or signs in to the platform, and caches the returned access token
+    /// for later requests.
+    pub async fn handshake_async(&self) -> Result<Value, EkError> {
+        let address = self.get_address();
+        let hk = match &self.backend {
+            Backend::Desktop => {
+                self.retry.run("Handshake", || Connection::handshake_request(
+                    &self.client,
+                    &address,
+                    self.get_app_key(),
+                )).await?
+            }
+            Backend::Platform(config) => {
+                self.retry.run("Sign in", || platform::sign_in(&self.client, config)).await?
+            }
+        };
+        self.tokens.set(Token::from_response(&hk)?).await;
+        Ok(hk)
+    }
+
+    async fn handshake_request(client: &reqwest::Client, address: &str, app_key: &str) -> Result<Value, EkError> {
+        let address = format!("{}/api/handshake", address);
         let json_body = json!({"AppKey": app_key,"AppScope": "trapi","ApiVersion": "1"});
-        let client = reqwest::blocking::Client::new();
         match client.post(address)
             .header("CONTENT-TYPE", "application/json")
             .header("x-tr-applicationid", app_key)
             .body(json_body.to_string())
-            .send() {
-            Err(e) => Err(EkError::ConnectionError(e.to_string())),
+            .send()
+            .await {
+            Err(e) => Err(e.into()),
             Ok(r) => {
-                match r.json() {
-                    Err(e) => Err(EkError::NoData(e.to_string())),
+                let status = r.status();
+                if !status.is_success() {
+                    return Err(EkError::ServerError(status.as_u16() as i64, r.text().await.unwrap_or_default()));
+                }
+                match r.json().await {
+                    Err(e) => Err(e.into()),
                     Ok(r) => {
                         debug!("Handshake: {:?}", r);
                         Ok(r)
@@ -79,72 +355,84 @@ impl Connection {
         }
     }
 
+    /// Blocking counterpart of `send_requests`.
     pub fn send_request_async_handler(&self, payloads: Vec<Value>, direction: Direction) -> Result<Vec<Value>, EkError> {
-        let rt = match tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(12)
-            .enable_all()
-            .build() {
-            Ok(r) => r,
-            Err(e) => return Err(EkError::ThreadError(e.to_string()))
-        };
+        self.runtime()?.block_on(self.send_requests(payloads, direction))
+    }
 
-        let app_key = self.get_app_key();
-        let address = self.get_address();
-        let handshake = self.handshake()?;
-        let access_token = Connection::bearer(handshake)?;
+    /// Sends every payload concurrently on the caller's runtime and collects the responses.
+    ///
+    /// Fails with the error of the first failed payload, see `send_requests_each` to keep the
+    /// responses of the others.
+    pub async fn send_requests(&self, payloads: Vec<Value>, direction: Direction) -> Result<Vec<Value>, EkError> {
+        let mut res = Vec::new();
+        for r in self.send_requests_each(payloads, direction).await? {
+            if let Some(v) = r? {
+                res.push(v)
+            }
+        }
+        Ok(res)
+    }
 
-        let delay = time::Duration::from_millis(250);
+    /// Sends every payload concurrently on the caller's runtime.
+    ///
+    /// # Returns
+    ///
+    /// The outcome of every payload in the order of `payloads`, or an error when no request could
+    /// be made at all (e.g. authentication failed)
+    pub async fn send_requests_each(
+        &self,
+        payloads: Vec<Value>,
+        direction: Direction,
+    ) -> Result<Vec<Result<Option<Value>, EkError>>, EkError> {
+        let ctx = Arc::new(RequestContext {
+            backend: self.backend.clone(),
+            client: self.client.clone(),
+            address: self.get_address(),
+            app_key: self.get_app_key().to_owned(),
+            tokens: self.tokens.clone(),
+            ticket_timeout: self.ticket_timeout,
+            retry: self.retry.clone(),
+            limiter: self.limiter.clone(),
+        });
+        // Fails early on authentication problems, and otherwise leaves a token cached for the requests
+        ctx.token().await?;
 
         let mut handles = Vec::with_capacity(payloads.len());
 
         for payload in payloads {
-            thread::sleep(delay);
-            handles.push(rt.spawn(
-                Connection::send_request_async(
-                    payload,
-                    direction.clone(),
-                    address.to_owned(),
-                    app_key.to_owned(),
-                    access_token.to_owned())))
+            handles.push(tokio::spawn(
+                Connection::send_request_async(payload, direction, ctx.clone())))
         }
 
-        let res = Connection::join_handles(handles, &rt)?;
-        return Ok(res);
+        Ok(Connection::join_handles(handles).await)
     }
 
-    fn join_handles(handles: Vec<JoinHandle<Result<Option<Value>, EkError>>>, rt: &Runtime) -> Result<Vec<Value>, EkError> {
-        let mut res = Vec::new();
+    async fn join_handles(handles: Vec<JoinHandle<Result<Option<Value>, EkError>>>) -> Vec<Result<Option<Value>, EkError>> {
+        let mut res = Vec::with_capacity(handles.len());
         for handle in handles {
-            match rt.block_on(handle) {
-                Ok(r) => {
-                    match r {
-                        Ok(opt) => {
-                            match opt {
-                                Some(v) => { res.push(v) }
-                                None => {}
-                            }
-                        }
-                        Err(e) => { return Err(e); }
-                    }
-                }
-                Err(e) => { return Err(EkError::ThreadError(e.to_string())); }
+            match handle.await {
+                Ok(r) => res.push(r),
+                Err(e) => res.push(Err(EkError::ThreadError(e.to_string())))
             }
         }
-        Ok(res)
+        res
     }
 
 
     pub fn bearer(hk: Value) -> Result<String, EkError> {
-        match hk.get("access_token") {
-            None => { Err(EkError::AuthError("Cannot get bearer access token".to_string())) }
-            Some(r) => { Ok(format!("Bearer {}", r.to_string())) }
-        }
+        Ok(Token::from_response(&hk)?.bearer())
     }
 
 
-    pub fn req_client(json_body: &Value, address: &str, app_key: &str, access_token: Option<&str>) -> reqwest::RequestBuilder {
-        let client = reqwest::Client::new();
-        let mut build = client.post(format!("{}/api/v1/data", address))
+    pub fn req_client(
+        client: &reqwest::Client,
+        json_body: &Value,
+        address: &str,
+        app_key: &str,
+        access_token: Option<&str>,
+    ) -> reqwest::RequestBuilder {
+        let build = client.post(format!("{}/api/v1/data", address))
             .header("CONTENT_TYPE", "application/json")
             .header("x-tr-applicationid", app_key)
             .json(json_body);
@@ -160,70 +448,287 @@ impl Connection {
         json!({"Entity": {"E": dir,"W": payload}})
     }
 
-    pub async fn request_executioner(req: reqwest::RequestBuilder) -> Result<Value, EkError> {
-        return match req.send().await {
+    /// Sends `req` once the rate limiter allows it and records the size of the response.
+    pub async fn request_executioner(
+        req: reqwest::RequestBuilder,
+        limiter: &RateLimiter,
+        direction: Direction,
+    ) -> Result<Value, EkError> {
+        limiter.acquire(direction).await?;
+        match req.send().await {
             Ok(r) => {
-                match r.json::<Value>().await {
+                let status = r.status();
+                if !status.is_success() {
+                    let text = r.text().await.unwrap_or_default();
+                    return match serde_json::from_str::<Value>(&text) {
+                        Ok(json_res) if json_res.get("ErrorCode").is_some() => Err(server_error(&json_res)),
+                        _ => Err(EkError::ServerError(status.as_u16() as i64, text))
+                    };
+                }
+                let bytes = match r.bytes().await {
+                    Ok(r) => r,
+                    Err(e) => return Err(e.into())
+                };
+                limiter.record_volume(direction, bytes.len());
+                match serde_json::from_slice::<Value>(&bytes) {
                     Ok(r) => Ok(r),
-                    Err(e) => Err(EkError::NoData(e.to_string()))
+                    Err(e) => Err(e.into())
                 }
             }
-            Err(e) => Err(EkError::ConnectionError(e.to_string()))
-        };
+            Err(e) => Err(e.into())
+        }
     }
 
     pub async fn send_request_async(
         payload: Value,
         direction: Direction,
-        address: String,
-        app_key: String,
-        access_token: String,
+        ctx: Arc<RequestContext>,
     ) -> Result<Option<Value>, EkError> {
         let body = Connection::entity_assembler(&payload, &direction);
+        let what = format!("{} request", direction);
 
-        loop {
-            let req: reqwest::RequestBuilder = Connection::req_client(&body, &address, &app_key, Some(&access_token));
-            let json_res = match Connection::request_executioner(req).await {
-                Ok(r) => r,
-                Err(e) => return Err(e)
-            };
-
-            match direction {
-                Direction::Datagrid => {
-                    match json_res.get("responses") {
-                        Some(r) => {
-                            match r[0].get("ticket") {
-                                None => return Ok(Some(json_res)),
-                                Some(ticket) => { Connection::ticket_req(ticket, &direction, &address, &app_key); }
-                            }
+        match &ctx.backend {
+            Backend::Desktop => {
+                ctx.retry.run(&what, || Connection::send_request_once(&body, direction, &ctx)).await
+            }
+            Backend::Platform(config) => {
+                ctx.retry.run(&what, || Connection::send_platform_request(&payload, direction, config, &ctx)).await
+            }
+        }
+    }
+
+    /// Sends a desktop payload to the platform endpoints and returns the result in the layout the
+    /// desktop proxy would have used.
+    async fn send_platform_request(
+        payload: &Value,
+        direction: Direction,
+        config: &PlatformConfig,
+        ctx: &RequestContext,
+    ) -> Result<Option<Value>, EkError> {
+        match direction {
+            Direction::Datagrid => {
+                let res = ctx.authorized(direction, |bearer| platform::datagrid_request(
+                    &ctx.client,
+                    config,
+                    payload,
+                    bearer,
+                )).await?;
+                Ok(Some(platform::datagrid_response(&res)))
+            }
+            Direction::TimeSeries => {
+                let mut data = Vec::new();
+                for ric in payload["rics"].as_array().unwrap_or(&Vec::new()) {
+                    let ric = ric.as_str().unwrap_or_default();
+                    let res = ctx.authorized(direction, |bearer| platform::timeseries_request(
+                        &ctx.client,
+                        config,
+                        payload,
+                        ric,
+                        bearer,
+                    )).await;
+                    match res {
+                        Ok(r) => data.push(platform::timeseries_data(ric, &r)),
+                        // The RIC itself was refused, the others in the chunk can still be used
+                        Err(EkError::ServerError(code, message)) if (400..500).contains(&code) && code != 401 && code != 429 => {
+                            data.push(platform::timeseries_error(ric, &code.to_string(), &message))
                         }
-                        None => {
-                            match json_res.get("ErrorCode") {
-                                None => return Ok(None),
-                                Some(ErrorCode) => {
-                                    match ErrorCode.as_u64().unwrap() {
-                                        2504u64 | 500u64 | 400u64 => {}
-                                        _ => return Err(EkError::Error(format!("{}: {}", ErrorCode, json_res["ErrorMessage"])))
-                                    }
-                                }
+                        Err(e) => return Err(e)
+                    }
+                }
+                Ok(Some(json!({"timeseriesData": data})))
+            }
+        }
+    }
+
+    async fn send_request_once(
+        body: &Value,
+        direction: Direction,
+        ctx: &RequestContext,
+    ) -> Result<Option<Value>, EkError> {
+        let json_res = ctx.authorized_request(body, direction).await?;
+
+        match direction {
+            Direction::Datagrid => {
+                match json_res.get("responses") {
+                    Some(r) => {
+                        match r[0].get("ticket") {
+                            None => Ok(Some(json_res)),
+                            Some(ticket) => {
+                                let res = Connection::ticket_req(ticket, estimated_wait(&r[0]), ctx).await?;
+                                Ok(Some(res))
                             }
                         }
                     }
+                    None => {
+                        warn!("Datagrid response without data: {}", json_res);
+                        Ok(None)
+                    }
                 }
-                Direction::TimeSeries => return Ok(Some(json_res))
             }
+            Direction::TimeSeries => Ok(Some(json_res))
         }
     }
 
-    pub async fn ticket_req(ticket: &Value, direction: &Direction, address: &str, app_key: &str) -> Result<Value, EkError> {
-        let payload = json!({"requests" : [{"ticket" : ticket}]});
-        let body = Connection::entity_assembler(&payload, &direction);
-        let req = Connection::req_client(&body, address, app_key, None);
-        Connection::request_executioner(req).await
+    /// Polls a Datagrid ticket until the server returns the data in place of a new ticket.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticket` - Ticket returned by the server
+    /// * `wait` - Estimated wait suggested by the server before the first poll
+    /// * `ctx` - Request context, its `ticket_timeout` bounds the total time spent polling
+    ///
+    /// # Returns
+    ///
+    /// The Datagrid response, or `EkError::TicketExpired` if it is not ready in time
+    pub async fn ticket_req(
+        ticket: &Value,
+        wait: time::Duration,
+        ctx: &RequestContext,
+    ) -> Result<Value, EkError> {
+        let start = time::Instant::now();
+        let mut ticket = ticket.to_owned();
+        let mut wait = wait;
+
+        loop {
+            if start.elapsed() + wait > ctx.ticket_timeout {
+                return Err(EkError::TicketExpired(clean_string(ticket.to_string())));
+            }
+            debug!("Ticket {}: polling in {:?}", ticket, wait);
+            tokio::time::sleep(wait).await;
+
+            let payload = json!({"requests" : [{"ticket" : ticket}]});
+            let body = Connection::entity_assembler(&payload, &Direction::Datagrid);
+            let json_res = ctx.authorized_request(&body, Direction::Datagrid).await?;
+
+            match json_res.get("responses") {
+                Some(r) => {
+                    match r[0].get("ticket") {
+                        None => return Ok(json_res),
+                        Some(t) => {
+                            wait = estimated_wait(&r[0]);
+                            ticket = t.to_owned();
+                        }
+                    }
+                }
+                None => return Err(server_error(&json_res))
+            }
+        }
+    }
+}
+
+/// Builds an `EkError::ServerError` from a response carrying `ErrorCode` and `ErrorMessage`.
+fn server_error(json_res: &Value) -> EkError {
+    let code = json_res["ErrorCode"].as_i64().unwrap_or_default();
+    EkError::ServerError(code, clean_string(json_res["ErrorMessage"].to_string()))
+}
+
+/// Wait suggested by the server through `estimatedDuration` (milliseconds) on a ticket response.
+fn estimated_wait(response: &Value) -> time::Duration {
+    match response["estimatedDuration"].as_u64() {
+        None => DEFAULT_TICKET_WAIT,
+        Some(ms) => time::Duration::from_millis(ms)
     }
 }
 
+/// Locations of the `.portInUse` file written by the Eikon and Workspace API proxies.
+fn port_in_use_files() -> Vec<PathBuf> {
+    let app_data = match env::var_os("APPDATA") {
+        Some(r) => PathBuf::from(r),
+        None => match env::var_os("HOME") {
+            Some(home) => Path::new(&home).join("Library").join("Application Support"),
+            None => return Vec::new()
+        }
+    };
+    vec![
+        app_data.join("Thomson Reuters").join("Eikon API Proxy").join(".portInUse"),
+        app_data.join("Refinitiv").join("Data API Proxy").join(".portInUse"),
+    ]
+}
+
+fn read_port_file(path: &Path) -> Option<u16> {
+    match fs::read_to_string(path) {
+        Ok(r) => r.trim().parse::<u16>().ok(),
+        Err(_) => None
+    }
+}
+
+/// Ports to probe, those read from `.portInUse` files first, followed by the defaults.
+fn candidate_ports(files: &[PathBuf]) -> Vec<u16> {
+    let mut ports: Vec<u16> = Vec::new();
+    let found = files.iter().filter_map(|f| read_port_file(f));
+    for port in found.chain(DEFAULT_PORTS) {
+        if !ports.contains(&port) {
+            ports.push(port);
+        }
+    }
+    ports
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_candidate_ports() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let file = dir.join(".portInUse");
+        fs::write(&file, "9060\n").unwrap();
+
+        let ports = candidate_ports(&[dir.join("missing"), file]);
+        assert_eq!(ports[0], 9060);
+        assert_eq!(ports[1], 9000);
+        assert_eq!(ports.len(), DEFAULT_PORTS.len());
+        assert!(ports.contains(&36036));
+    }
+
+    #[test]
+    fn test_runtime_reused() {
+        let conn = Connection::new("key".to_string(), "127.0.0.1".to_string(), 9000);
+        let first = conn.runtime().unwrap() as *const Runtime;
+        let second = conn.runtime().unwrap() as *const Runtime;
+        assert_eq!(first, second);
+
+        // Clones share the runtime and the request budget
+        let shared = conn.clone();
+        assert_eq!(shared.runtime().unwrap() as *const Runtime, first);
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            match conn.handshake() {
+                Err(EkError::ThreadError(_)) => {}
+                _ => panic!("Expected blocking inside a runtime to be refused"),
+            }
+            shared.limiter.acquire(Direction::Datagrid).await.unwrap();
+        });
+        assert_eq!(conn.remaining_daily_requests(Direction::Datagrid), shared.remaining_daily_requests(Direction::Datagrid));
+        assert_eq!(conn.remaining_daily_requests(Direction::Datagrid), Limits::default().requests_per_day - 1);
+    }
+
+    #[test]
+    fn test_estimated_wait() {
+        let response = json!({"estimatedDuration": 2500, "ticket": "abc"});
+        assert_eq!(estimated_wait(&response), time::Duration::from_millis(2500));
+        assert_eq!(estimated_wait(&json!({"ticket": "abc"})), DEFAULT_TICKET_WAIT);
+    }
+
+    #[test]
+    fn test_ticket_expired() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let ctx = RequestContext {
+            backend: Backend::Desktop,
+            client: reqwest::Client::new(),
+            address: "http://127.0.0.1:1".to_string(),
+            app_key: "key".to_string(),
+            tokens: Arc::new(TokenCache::new()),
+            ticket_timeout: time::Duration::from_secs(1),
+            retry: RetryPolicy::default(),
+            limiter: Arc::new(RateLimiter::new()),
+        };
+        let res = rt.block_on(Connection::ticket_req(&json!("abc"), time::Duration::from_secs(10), &ctx));
+        match res {
+            Err(EkError::TicketExpired(t)) => assert_eq!(t, "abc"),
+            _ => panic!("Expected the ticket to expire"),
+        }
+    }
 }
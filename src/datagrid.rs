@@ -1,13 +1,19 @@
 use std::collections::HashMap;
-use std::cmp::min;
+use std::fmt;
+use std::str::FromStr;
+use serde::Deserialize;
 use serde_json::{json, Value};
 use polars::prelude::*;
 use chrono::prelude::*;
 use crate::connection::{Connection, Direction};
-use crate::utils::{clean_string, EkResults, EkError};
+use crate::report::{Failure, Partial, Report};
+use crate::utils::{clean_string, field_string, parse_timestamp, EkError};
+use log::warn;
 
 
-enum Frequency {
+/// Period of the `Frq` parameter of a Datagrid request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Frequency {
     Daily,
     Weekly,
     Monthly,
@@ -17,37 +23,120 @@ enum Frequency {
 }
 
 impl Frequency {
-    fn new(frq: &str) -> Self {
-        match frq.to_lowercase().as_str() {
-            "aw" | "w" | "cw" => { Self::Weekly }
-            "am" | "m" | "cm" => { Self::Monthly }
-            "aq" | "q" | "fq" | "fi" | "cq" | "f" => { Self::Quarterly }
-            "fs" | "fh" | "cs" | "ch" => { Self::SemiAnnual }
-            "ay" | "y" | "fy" | "cy" => { Self::Annual }
-            _ => { Self::Daily }
+    /// Eikon code of the frequency, as used for the `Frq` parameter.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Daily => { "D" }
+            Self::Weekly => { "W" }
+            Self::Monthly => { "M" }
+            Self::Quarterly => { "Q" }
+            Self::SemiAnnual => { "FS" }
+            Self::Annual => { "Y" }
         }
     }
 }
 
+impl FromStr for Frequency {
+    type Err = EkError;
+
+    /// Parses Eikon `Frq` codes, calendar (`C`), fiscal (`F`) and fixed (`A`) variants included, and
+    /// their English names.
+    fn from_str(frq: &str) -> Result<Self, Self::Err> {
+        match frq.trim().to_lowercase().as_str() {
+            "d" | "ad" | "cd" | "daily" | "day" => { Ok(Self::Daily) }
+            "w" | "aw" | "cw" | "weekly" | "week" => { Ok(Self::Weekly) }
+            "m" | "am" | "cm" | "monthly" | "month" => { Ok(Self::Monthly) }
+            "q" | "aq" | "fq" | "fi" | "cq" | "f" | "quarterly" | "quarter" => { Ok(Self::Quarterly) }
+            "fs" | "fh" | "cs" | "ch" | "as" | "ah" | "semiannual" | "semi-annual" => { Ok(Self::SemiAnnual) }
+            "y" | "ay" | "fy" | "cy" | "annual" | "yearly" | "year" => { Ok(Self::Annual) }
+            _ => { Err(EkError::UnknownFrequency(frq.to_string())) }
+        }
+    }
+}
+
+impl fmt::Display for Frequency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+
+/// How a Datagrid column holding values of different JSON types is converted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MixedTypes {
+    /// Keep the whole column as strings
+    #[default]
+    Utf8,
+    /// Use the most common type and turn the values that do not fit into nulls
+    Majority,
+    /// Fail the conversion
+    Error,
+}
+
+/// What to do with the cells the server could not fill, listed in the `error` array of a response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellErrors {
+    /// Leave the cells null and return the errors next to the data
+    #[default]
+    Collect,
+    /// Fail with the first error
+    Fail,
+}
+
+/// Error the server returned for one cell.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CellError {
+    pub instrument: String,
+    /// Column name, as set by the header mode
+    pub field: String,
+    pub code: i64,
+    pub message: String,
+}
+
+impl CellError {
+    fn to_error(&self) -> EkError {
+        EkError::CellError(self.code, format!("{} {}: {}", self.instrument, self.field, self.message))
+    }
+}
 
 pub struct Datagrid {
     connection: Connection,
+    headers: HeaderMode,
+    mixed_types: MixedTypes,
+    cell_errors: CellErrors,
 }
 
 impl Datagrid {
     pub fn new(c: Connection) -> Self {
         Self {
-            connection: c
+            connection: c,
+            headers: HeaderMode::default(),
+            mixed_types: MixedTypes::default(),
+            cell_errors: CellErrors::default(),
         }
     }
 
+    pub fn set_cell_errors(&mut self, policy: CellErrors) {
+        self.cell_errors = policy;
+    }
+
+    pub fn set_header_mode(&mut self, mode: HeaderMode) {
+        self.headers = mode;
+    }
+
+    pub fn set_mixed_types(&mut self, policy: MixedTypes) {
+        self.mixed_types = policy;
+    }
+
     fn assemble_payload(
         &self,
         instruments: Vec<String>,
         fields: &Value,
         param: &Option<HashMap<String, String>>,
     ) -> Value {
-        let res = match param {
+        match param {
             None => {
                 json!(
                     {
@@ -69,49 +158,165 @@ impl Datagrid {
                     }
                 )
             }
-        };
-        return res;
+        }
+    }
+
+    /// Splits the instruments into chunks small enough for one request each.
+    fn payloads(
+        &self,
+        instruments: Vec<String>,
+        fields: &Value,
+        parameters: &Option<HashMap<String, String>>,
+    ) -> Result<Vec<Value>, EkError> {
+        let group_size = groups(parameters)?;
+        let mut payloads: Vec<Value> = Vec::new();
+        for chunk in instruments.chunks(group_size) {
+            let inst_chunk = chunk.to_vec();
+            payloads.push(self.assemble_payload(inst_chunk, fields, parameters));
+        }
+        Ok(payloads)
     }
 
+    /// Blocking counterpart of `get_datagrid_async`, must not be called from within an async runtime.
     pub fn get_datagrid(
         &self,
         instruments: Vec<String>,
         fields: Value,
         parameters: Option<HashMap<String, String>>,
-        settings: HashMap<String, bool>,
-    ) -> EkResults {
-        let direction = Direction::Datagrid;
-        let group_size = match groups(&parameters) {
-            Ok(r) => r,
-            Err(e) => return EkResults::Err(e)
-        };
-        let mut payloads: Vec<Value> = Vec::new();
-        for chunk in instruments.chunks(group_size).into_iter() {
-            let inst_chunk = chunk.to_vec();
-            payloads.push(self.assemble_payload(inst_chunk, &fields, &parameters));
+    ) -> Result<DataFrame, EkError> {
+        self.connection.runtime()?.block_on(self.get_datagrid_async(instruments, fields, parameters))
+    }
+
+    /// Blocking counterpart of `get_datagrid_raw_async`, must not be called from within an async runtime.
+    pub fn get_datagrid_raw(
+        &self,
+        instruments: Vec<String>,
+        fields: Value,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<Vec<Value>, EkError> {
+        self.connection.runtime()?.block_on(self.get_datagrid_raw_async(instruments, fields, parameters))
+    }
+
+    /// Blocking counterpart of `get_datagrid_with_errors_async`, must not be called from within an async runtime.
+    pub fn get_datagrid_with_errors(
+        &self,
+        instruments: Vec<String>,
+        fields: Value,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<(DataFrame, DataFrame), EkError> {
+        self.connection.runtime()?.block_on(self.get_datagrid_with_errors_async(instruments, fields, parameters))
+    }
+
+    /// Blocking counterpart of `get_datagrid_partial_async`, must not be called from within an async runtime.
+    pub fn get_datagrid_partial(
+        &self,
+        instruments: Vec<String>,
+        fields: Value,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<Partial<DataFrame>, EkError> {
+        self.connection.runtime()?.block_on(self.get_datagrid_partial_async(instruments, fields, parameters))
+    }
+
+    /// Downloads the data grid and combines every chunk into one DataFrame.
+    ///
+    /// Cells the server could not fill are left null, see `get_datagrid_with_errors_async` for the
+    /// reason, or set `CellErrors::Fail` to fail instead.
+    pub async fn get_datagrid_async(
+        &self,
+        instruments: Vec<String>,
+        fields: Value,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<DataFrame, EkError> {
+        let (df, errors) = self.get_datagrid_with_errors_async(instruments, fields, parameters).await?;
+        if errors.height() > 0 {
+            warn!("{} cells could not be filled", errors.height());
         }
+        Ok(df)
+    }
 
-        let res = match self.connection.send_request_async_handler(payloads, direction) {
-            Ok(r) => r,
-            Err(e) => return EkResults::Err(e)
-        };
+    /// Downloads the data grid together with the errors of the cells the server could not fill.
+    ///
+    /// # Returns
+    ///
+    /// The data, and a DataFrame with the `Instrument`, `Field`, `Code` and `Message` of every
+    /// failed cell
+    pub async fn get_datagrid_with_errors_async(
+        &self,
+        instruments: Vec<String>,
+        fields: Value,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<(DataFrame, DataFrame), EkError> {
+        let res = self.get_datagrid_raw_async(instruments, fields.to_owned(), parameters).await?;
+        let (df, errors) = self.to_dataframe(res, &fields)?;
+        Ok((df, errors_dataframe(&errors)?))
+    }
 
-        if res.is_empty() {
-            return EkResults::Err(EkError::NoData("No data returned from Refinitiv".to_string()));
+    /// Converts the responses, failing on the first cell error if asked to.
+    fn to_dataframe(&self, json_like: Vec<Value>, fields: &Value) -> Result<(DataFrame, Vec<CellError>), EkError> {
+        let (df, errors) = to_dataframe(json_like, fields, self.headers, self.mixed_types)?;
+        match (self.cell_errors, errors.first()) {
+            (CellErrors::Fail, Some(e)) => Err(e.to_error()),
+            _ => Ok((df, errors))
         }
+    }
 
-        if *settings.get("raw").unwrap_or(&false) {
-            EkResults::Raw(res)
-        } else {
-            let field_name = match settings.get("field_name") {
-                None => false,
-                Some(r) => r.to_owned()
-            };
-            match to_dataframe(res, field_name) {
-                Ok(r) => { EkResults::DF(r) }
-                Err(e) => { EkResults::Err(e) }
+    /// Downloads the data grid, keeping the data of every chunk that succeeded.
+    ///
+    /// # Returns
+    ///
+    /// The combined DataFrame with a report of the chunks that failed, or an error when no request
+    /// could be made at all
+    pub async fn get_datagrid_partial_async(
+        &self,
+        instruments: Vec<String>,
+        fields: Value,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<Partial<DataFrame>, EkError> {
+        let payloads = self.payloads(instruments, &fields, &parameters)?;
+        let res = self.connection.send_requests_each(payloads.clone(), Direction::Datagrid).await?;
+
+        let mut report = Report::default();
+        let mut responses = Vec::new();
+        for (payload, r) in payloads.iter().zip(res) {
+            match r {
+                Err(e) => {
+                    let f = Failure::from_chunk(payload, &e);
+                    warn!("Could not download {:?}: {}", f.instruments, f.message);
+                    report.chunks.push(f)
+                }
+                Ok(None) => {}
+                Ok(Some(r)) => responses.push(r)
             }
         }
+        if responses.is_empty() {
+            return Ok(Partial { data: None, report });
+        }
+        let (df, errors) = self.to_dataframe(responses, &fields)?;
+        for e in errors {
+            report.rics.push(Failure {
+                code: Some(e.code.to_string()),
+                message: format!("{}: {}", e.field, e.message),
+                instruments: vec![e.instrument],
+                start_date: parameters.as_ref().and_then(|p| p.get("SDate").cloned()),
+                end_date: parameters.as_ref().and_then(|p| p.get("EDate").cloned()),
+            });
+        }
+        Ok(Partial { data: Some(df), report })
+    }
+
+    /// Downloads the data grid and returns the JSON response of every chunk as sent by the server.
+    pub async fn get_datagrid_raw_async(
+        &self,
+        instruments: Vec<String>,
+        fields: Value,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<Vec<Value>, EkError> {
+        let payloads = self.payloads(instruments, &fields, &parameters)?;
+        let res = self.connection.send_requests(payloads, Direction::Datagrid).await?;
+        if res.is_empty() {
+            return Err(EkError::NoData("No data returned from Refinitiv".to_string()));
+        }
+        Ok(res)
     }
 }
 
@@ -122,16 +327,22 @@ fn groups(parameters: &Option<HashMap<String, String>>) -> Result<usize, EkError
         Some(param) => {
             match param.get("SDate") {
                 None => { max_instruments }
-                Some(SDate) => {
-                    let start_date = str_to_date(SDate.as_str())?;
+                Some(sdate) => {
+                    let start_date = str_to_date("SDate", sdate)?;
                     let end_date = match param.get("EDate") {
                         None => { Utc::now().date_naive() }
                         Some(value) => {
-                            str_to_date(value.as_str())?
+                            str_to_date("EDate", value)?
                         }
                     };
+                    if start_date > end_date {
+                        return Err(EkError::DateError(format!("SDate {} is after EDate {}", start_date, end_date)));
+                    }
                     let dur = end_date.signed_duration_since(start_date);
-                    let frq = Frequency::new(param.get("Frq").unwrap_or(&String::from("d")).as_str());
+                    let frq = match param.get("Frq") {
+                        None => Frequency::Daily,
+                        Some(r) => r.parse::<Frequency>()?
+                    };
                     let rows_pr = match frq {
                         Frequency::Daily => { dur.num_days() as f32 }
                         Frequency::Weekly => { (dur.num_days() as f32) / 7f32 }
@@ -140,7 +351,8 @@ fn groups(parameters: &Option<HashMap<String, String>>) -> Result<usize, EkError
                         Frequency::SemiAnnual => { (dur.num_days() as f32) / 180f32 }
                         Frequency::Annual => { (dur.num_days() as f32) / 365f32 }
                     };
-                    min((max_rows as f32 / rows_pr as f32).floor() as usize, max_instruments)
+                    // A window longer than max_rows still goes out one instrument at a time
+                    ((max_rows as f32 / rows_pr).floor() as usize).clamp(1, max_instruments)
                 }
             }
         }
@@ -149,36 +361,208 @@ fn groups(parameters: &Option<HashMap<String, String>>) -> Result<usize, EkError
     Ok(max_group_size)
 }
 
-fn fetch_headers(json_like: &Value, field_name: bool) -> Option<Vec<String>> {
-    let headers = match json_like["responses"][0]["headers"][0]
-        .as_array() {
-        None => { return None; }
-        Some(r) => { r }
-    };
+/// Name of the column holding the instrument, which has no field code.
+const INSTRUMENT: &str = "Instrument";
+
+/// Joins the field code and display name of a column in `HeaderMode::Both`.
+pub const LEVEL_SEPARATOR: &str = "|";
+
+/// How Datagrid columns are named.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderMode {
+    /// Name shown in Eikon, e.g. `Gross Profit`
+    #[default]
+    DisplayName,
+    /// Field code as returned by the server, e.g. `TR.GROSSPROFIT`
+    FieldCode,
+    /// Field code as requested, with its parameters, e.g. `TR.GrossProfit(Curn=EUR)`
+    FieldCodeWithParams,
+    /// Field code with parameters and display name, joined by `LEVEL_SEPARATOR` so that they can be
+    /// split into a two-level header
+    Both,
+}
+
+/// Column names of a response, the instrument column is always named `Instrument`.
+///
+/// # Arguments
+///
+/// * `json_like` - Datagrid response
+/// * `mode` - How columns are named
+/// * `fields` - The requested fields, as made by `field_builder`, used for their parameters
+fn fetch_headers(json_like: &Value, mode: HeaderMode, fields: &Value) -> Option<Vec<String>> {
+    let headers = json_like["responses"][0]["headers"][0].as_array()?;
+    let requested = fields.as_array().map(|f| f.as_slice()).unwrap_or_default();
+    let mut used = vec![false; requested.len()];
 
     let mut names: Vec<String> = Vec::new();
-    // TODO, headers contains two fields displayName and field, The last one is not available for instrument
     for value in headers {
-        if field_name {
-            let n = match value.get("field") {
-                None => value["displayName"].to_string(),
-                Some(r) => r.to_string()
-            };
-            names.push(n);
-        } else {
-            names.push(clean_string(value["displayName"].to_string()))
+        let display = clean_string(value["displayName"].to_string());
+        let field = match value["field"].as_str() {
+            // The instrument column has no field code
+            None => {
+                names.push(INSTRUMENT.to_string());
+                continue;
+            }
+            Some(r) => r
+        };
+        // Requested fields come back in order, the same code may be requested with different parameters
+        let with_params = match requested.iter().enumerate()
+            .position(|(i, f)| !used[i] && f["name"].as_str().is_some_and(|n| n.eq_ignore_ascii_case(field))) {
+            None => field.to_string(),
+            Some(i) => {
+                used[i] = true;
+                field_string(&requested[i])
+            }
+        };
+        names.push(match mode {
+            HeaderMode::DisplayName => display,
+            HeaderMode::FieldCode => field.to_string(),
+            HeaderMode::FieldCodeWithParams => with_params,
+            HeaderMode::Both => format!("{}{}{}", with_params, LEVEL_SEPARATOR, display),
+        });
+    }
+    Some(dedup_headers(names))
+}
+
+/// Makes repeated names unique by appending `_1`, `_2`, ... to later occurrences.
+fn dedup_headers(names: Vec<String>) -> Vec<String> {
+    let mut res: Vec<String> = Vec::with_capacity(names.len());
+    for name in names {
+        let mut unique = name.to_owned();
+        let mut n = 0;
+        while res.contains(&unique) {
+            n += 1;
+            unique = format!("{}_{}", name, n);
         }
+        res.push(unique);
     }
-    Some(names)
+    res
 }
 
-fn to_dataframe(json_like: Vec<Value>, field_name: bool) -> Result<DataFrame, EkError> {
+/// Type of a single Datagrid cell, `None` for nulls and empty strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CellType {
+    Bool,
+    Int,
+    Float,
+    Date,
+    Datetime,
+    Utf8,
+}
+
+impl CellType {
+    fn new(v: &Value) -> Option<Self> {
+        match v {
+            Value::Null => None,
+            Value::Bool(_) => Some(CellType::Bool),
+            Value::Number(n) => Some(if n.is_f64() { CellType::Float } else { CellType::Int }),
+            Value::String(s) if s.is_empty() => None,
+            Value::String(s) => {
+                if NaiveDate::parse_from_str(s, "%Y-%m-%d").is_ok() {
+                    Some(CellType::Date)
+                } else if parse_timestamp(s).is_some() {
+                    Some(CellType::Datetime)
+                } else {
+                    Some(CellType::Utf8)
+                }
+            }
+            _ => Some(CellType::Utf8)
+        }
+    }
+
+    /// Type of a column holding `values`, integers are widened to floats and dates to datetimes
+    /// when the column mixes them.
+    fn infer(name: &str, values: &[&Value], policy: MixedTypes) -> Result<Self, EkError> {
+        let mut counts: Vec<(CellType, usize)> = Vec::new();
+        for t in values.iter().filter_map(|v| CellType::new(v)) {
+            match counts.iter_mut().find(|(c, _)| *c == t) {
+                None => counts.push((t, 1)),
+                Some((_, n)) => *n += 1
+            }
+        }
+        widen(&mut counts, CellType::Int, CellType::Float);
+        widen(&mut counts, CellType::Date, CellType::Datetime);
+
+        match counts.len() {
+            0 => Ok(CellType::Utf8),
+            1 => Ok(counts[0].0),
+            _ => {
+                match policy {
+                    MixedTypes::Utf8 => Ok(CellType::Utf8),
+                    // Ties go to the type seen first
+                    MixedTypes::Majority => Ok(counts.iter().rev().max_by_key(|(_, n)| *n).unwrap().0),
+                    MixedTypes::Error => {
+                        let types = counts.iter().map(|(t, _)| format!("{:?}", t)).collect::<Vec<String>>();
+                        Err(EkError::NoDataFrame(format!("Column {} mixes {}", name, types.join(", "))))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Builds the column, values that do not fit the type become nulls.
+    fn series(&self, name: &str, values: &[&Value]) -> Series {
+        let fits = |v: &&Value| CellType::new(v).is_some();
+        match self {
+            CellType::Bool => {
+                Series::new(name, values.iter().map(|v| v.as_bool()).collect::<Vec<Option<bool>>>())
+            }
+            CellType::Int => {
+                Series::new(name, values.iter().map(|v| v.as_i64()).collect::<Vec<Option<i64>>>())
+            }
+            CellType::Float => {
+                Series::new(name, values.iter().map(|v| v.as_f64()).collect::<Vec<Option<f64>>>())
+            }
+            CellType::Date => {
+                let v = values.iter()
+                    .map(|v| v.as_str().and_then(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()))
+                    .collect::<Vec<Option<NaiveDate>>>();
+                DateChunked::from_naive_date_options(name, v).into_series()
+            }
+            CellType::Datetime => {
+                let v = values.iter()
+                    .map(|v| v.as_str().and_then(parse_timestamp))
+                    .collect::<Vec<Option<NaiveDateTime>>>();
+                DatetimeChunked::from_naive_datetime_options(name, v, TimeUnit::Milliseconds).into_series()
+            }
+            CellType::Utf8 => {
+                let v = values.iter()
+                    .map(|v| match v {
+                        Value::String(s) if fits(v) => Some(s.to_owned()),
+                        v if fits(v) => Some(v.to_string()),
+                        _ => None
+                    })
+                    .collect::<Vec<Option<String>>>();
+                Series::new(name, v)
+            }
+        }
+    }
+}
+
+/// Adds the count of `narrow` to `wide` when a column holds both.
+fn widen(counts: &mut Vec<(CellType, usize)>, narrow: CellType, wide: CellType) {
+    let n = match counts.iter().position(|(t, _)| *t == narrow) {
+        Some(i) if counts.iter().any(|(t, _)| *t == wide) => counts.remove(i).1,
+        _ => return
+    };
+    if let Some((_, w)) = counts.iter_mut().find(|(t, _)| *t == wide) {
+        *w += n;
+    }
+}
+
+fn to_dataframe(
+    json_like: Vec<Value>,
+    fields: &Value,
+    mode: HeaderMode,
+    mixed_types: MixedTypes,
+) -> Result<(DataFrame, Vec<CellError>), EkError> {
 
     // Extract headers
     let mut found = false;
     let mut headers: Vec<String> = Vec::new();
     for request in &json_like {
-        match fetch_headers(request, field_name) {
+        match fetch_headers(request, mode, fields) {
             None => { continue; }
             Some(r) => {
                 headers = r;
@@ -195,10 +579,7 @@ fn to_dataframe(json_like: Vec<Value>, field_name: bool) -> Result<DataFrame, Ek
     let mut df_vec: Vec<Series> = Vec::with_capacity(headers.len());
 
     for col in 0..headers.len() {
-        let mut ser_string: Vec<Option<String>> = Vec::new(); // Update capacity
-        // let mut ser_f64: Vec<Option<f64>> = Vec::new();
-        // let mut numeric: bool = false;
-
+        let mut values: Vec<&Value> = Vec::new();
         for request in &json_like {
             let rows = match request["responses"][0]["data"]
                 .as_array() {
@@ -206,35 +587,176 @@ fn to_dataframe(json_like: Vec<Value>, field_name: bool) -> Result<DataFrame, Ek
                 Some(r) => r
             };
             for row in rows {
-                ser_string.push(Some(clean_string(row[col].to_string())));
-                // numeric = row[col].is_number();
-                // match numeric {
-                //     true => {
-                //         ser_f64.push(row[col].as_f64())
-                //     }
-                //     false => {
-                //         ser_string.push(Some(clean_string(row[col].to_string())));
-                //     }
-                // }
+                values.push(&row[col]);
             }
         }
-        df_vec.push(Series::new(headers[col].as_str(), ser_string))
-        // match numeric {
-        //     true => df_vec.push(Series::new(headers[col].as_str(), ser_f64)),
-        //     false => df_vec.push(Series::new(headers[col].as_str(), ser_string))
-        // }
+        let kind = CellType::infer(&headers[col], &values, mixed_types)?;
+        df_vec.push(kind.series(headers[col].as_str(), &values))
     }
-    match DataFrame::new(df_vec) {
-        Ok(r) => Ok(r),
-        Err(e) => Err(EkError::NoDataFrame(e.to_string()))
+    Ok((DataFrame::new(df_vec)?, cell_errors(&json_like, &headers)))
+}
+
+/// Reads the `error` array of every response, `row` and `col` index the data of that response.
+fn cell_errors(json_like: &[Value], headers: &[String]) -> Vec<CellError> {
+    let mut res = Vec::new();
+    for request in json_like {
+        let response = &request["responses"][0];
+        for e in response["error"].as_array().map(|e| e.as_slice()).unwrap_or_default() {
+            let row = e["row"].as_u64().unwrap_or_default() as usize;
+            let col = e["col"].as_u64().unwrap_or_default() as usize;
+            res.push(CellError {
+                instrument: clean_string(response["data"][row][0].to_string()),
+                field: headers.get(col).cloned().unwrap_or_default(),
+                code: e["code"].as_i64().unwrap_or_default(),
+                message: clean_string(e["message"].to_string()),
+            });
+        }
     }
+    res
+}
+
+fn errors_dataframe(errors: &[CellError]) -> Result<DataFrame, EkError> {
+    Ok(DataFrame::new(vec![
+        Series::new(INSTRUMENT, errors.iter().map(|e| e.instrument.as_str()).collect::<Vec<&str>>()),
+        Series::new("Field", errors.iter().map(|e| e.field.as_str()).collect::<Vec<&str>>()),
+        Series::new("Code", errors.iter().map(|e| e.code).collect::<Vec<i64>>()),
+        Series::new("Message", errors.iter().map(|e| e.message.as_str()).collect::<Vec<&str>>()),
+    ])?)
+}
+
+/// Parses the date of the `name` request parameter.
+fn str_to_date(name: &str, d: &str) -> Result<NaiveDate, EkError> {
+    NaiveDate::parse_from_str(d, "%Y-%m-%d").map_err(|e| EkError::DateError(format!(
+        "Could not parse {} {} as a date ({}), please supply an ISO8601 date such as 2020-01-31", name, d, e
+    )))
 }
 
-fn str_to_date(d: &str) -> Result<NaiveDate, EkError> {
-    match NaiveDate::parse_from_str(d, "%Y-%m-%d") {
-        Ok(r) => { Ok(r) }
-        Err(e) => {
-            Err(EkError::DateError("Could not parse SDate string as Date, please supply a ISO8601 compliant Date format".to_string()))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(data: Value) -> Value {
+        json!({"responses": [{
+            "headers": [[{"displayName": "Instrument"}, {"displayName": "Gross Profit", "field": "TR.GROSSPROFIT"}, {"displayName": "Date", "field": "TR.GROSSPROFIT.DATE"}]],
+            "data": data
+        }]})
+    }
+
+    #[test]
+    fn test_to_dataframe() {
+        let res = vec![
+            response(json!([["XOM", 1200, "2002-01-31"], ["GME", null, ""]])),
+            response(json!([["AAPL", 10.5, "2002-02-28"]])),
+        ];
+        let df = to_dataframe(res, &json!([]), HeaderMode::DisplayName, MixedTypes::Utf8).unwrap().0;
+        assert_eq!(df.get_column_names(), vec![INSTRUMENT, "Gross Profit", "Date"]);
+        assert_eq!(df.dtypes(), vec![DataType::Utf8, DataType::Float64, DataType::Date]);
+        assert_eq!(df.column("Gross Profit").unwrap().null_count(), 1);
+        assert_eq!(df.column("Date").unwrap().null_count(), 1);
+        assert_eq!(df.column("Gross Profit").unwrap().f64().unwrap().get(0), Some(1200f64));
+    }
+
+    #[test]
+    fn test_mixed_types() {
+        let res = || vec![response(json!([["XOM", 1200, "2002-01-31"], ["GME", "n/a", "2002-01-31"], ["AAPL", 5, "2002-01-31"]]))];
+
+        let df = to_dataframe(res(), &json!([]), HeaderMode::DisplayName, MixedTypes::Utf8).unwrap().0;
+        assert_eq!(df.column("Gross Profit").unwrap().utf8().unwrap().get(1), Some("n/a"));
+        assert_eq!(df.column("Gross Profit").unwrap().utf8().unwrap().get(0), Some("1200"));
+
+        let df = to_dataframe(res(), &json!([]), HeaderMode::DisplayName, MixedTypes::Majority).unwrap().0;
+        assert_eq!(df.column("Gross Profit").unwrap().dtype(), &DataType::Int64);
+        assert_eq!(df.column("Gross Profit").unwrap().null_count(), 1);
+
+        match to_dataframe(res(), &json!([]), HeaderMode::DisplayName, MixedTypes::Error) {
+            Err(EkError::NoDataFrame(e)) => assert!(e.contains("Gross Profit")),
+            r => panic!("Expected a mixed type error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_cell_errors() {
+        let mut res = response(json!([["XOM", 1200, "2002-01-31"], ["NOPE", "", ""]]));
+        res["responses"][0]["error"] = json!([
+            {"code": 416, "col": 1, "row": 1, "message": "Unable to collect data for the field 'TR.GROSSPROFIT'"},
+            {"code": 416, "col": 2, "row": 1, "message": "Unable to collect data for the field 'TR.GROSSPROFIT.DATE'"}
+        ]);
+        let (df, errors) = to_dataframe(vec![res.to_owned()], &json!([]), HeaderMode::FieldCode, MixedTypes::Error).unwrap();
+        assert_eq!(df.column("TR.GROSSPROFIT").unwrap().null_count(), 1);
+        assert_eq!(errors[0], CellError {
+            instrument: "NOPE".to_string(),
+            field: "TR.GROSSPROFIT".to_string(),
+            code: 416,
+            message: "Unable to collect data for the field 'TR.GROSSPROFIT'".to_string(),
+        });
+        assert_eq!(errors[1].field, "TR.GROSSPROFIT.DATE");
+
+        let errors = errors_dataframe(&errors).unwrap();
+        assert_eq!(errors.get_column_names(), vec![INSTRUMENT, "Field", "Code", "Message"]);
+        assert_eq!(errors.height(), 2);
+
+        let mut dg = Datagrid::new(Connection::new("key".to_string(), "127.0.0.1".to_string(), 9000));
+        dg.set_cell_errors(CellErrors::Fail);
+        match dg.to_dataframe(vec![res], &json!([])) {
+            Err(EkError::CellError(416, e)) => assert!(e.starts_with("NOPE Gross Profit")),
+            r => panic!("Expected a cell error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_frequency() {
+        assert_eq!("FQ".parse::<Frequency>().unwrap(), Frequency::Quarterly);
+        assert_eq!("cy".parse::<Frequency>().unwrap(), Frequency::Annual);
+        assert_eq!("weekly".parse::<Frequency>().unwrap(), Frequency::Weekly);
+        assert!(matches!("weekley".parse::<Frequency>(), Err(EkError::UnknownFrequency(_))));
+
+        let mut params = HashMap::new();
+        params.insert("SDate".to_string(), "2020-01-01".to_string());
+        params.insert("EDate".to_string(), "2021-01-01".to_string());
+        params.insert("Frq".to_string(), Frequency::Monthly.to_string());
+        assert_eq!(groups(&Some(params.to_owned())).unwrap(), 4098);
+        params.insert("Frq".to_string(), "Mnth".to_string());
+        assert!(groups(&Some(params.to_owned())).is_err());
+        params.insert("EDate".to_string(), "31/12/2020".to_string());
+        match groups(&Some(params)) {
+            Err(EkError::DateError(m)) => assert!(m.contains("EDate 31/12/2020") && m.contains("ISO8601"), "{}", m),
+            r => panic!("Expected a date error, got {:?}", r),
         }
+
+        let mut params = HashMap::new();
+        params.insert("SDate".to_string(), "2021-01-01".to_string());
+        params.insert("EDate".to_string(), "2020-01-01".to_string());
+        match groups(&Some(params.to_owned())) {
+            Err(EkError::DateError(m)) => assert!(m.contains("after EDate 2020-01-01"), "{}", m),
+            r => panic!("Expected a date error, got {:?}", r),
+        }
+        // 200 years of daily rows do not fit one instrument, which is still sent on its own
+        params.insert("SDate".to_string(), "1820-01-01".to_string());
+        params.insert("EDate".to_string(), "2020-01-01".to_string());
+        assert_eq!(groups(&Some(params)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_fetch_headers() {
+        let res = json!({"responses": [{"headers": [[
+            {"displayName": "Instrument"},
+            {"displayName": "Close Price", "field": "TR.CLOSE"},
+            {"displayName": "Date", "field": "TR.CLOSE.DATE"},
+            {"displayName": "Close Price", "field": "TR.CLOSE"},
+            {"displayName": "Date", "field": "TR.VOLUME.DATE"}
+        ]]}]});
+        let fields = json!([
+            {"name": "TR.Close", "parameters": {"Curn": "EUR"}},
+            {"name": "TR.CLOSE.DATE"},
+            {"name": "TR.Close", "parameters": {"Curn": "USD"}},
+            {"name": "TR.VOLUME.DATE"}
+        ]);
+        let headers = |mode| fetch_headers(&res, mode, &fields).unwrap();
+
+        assert_eq!(headers(HeaderMode::DisplayName), vec!["Instrument", "Close Price", "Date", "Close Price_1", "Date_1"]);
+        assert_eq!(headers(HeaderMode::FieldCode), vec!["Instrument", "TR.CLOSE", "TR.CLOSE.DATE", "TR.CLOSE_1", "TR.VOLUME.DATE"]);
+        assert_eq!(headers(HeaderMode::FieldCodeWithParams), vec!["Instrument", "TR.Close(Curn=EUR)", "TR.CLOSE.DATE", "TR.Close(Curn=USD)", "TR.VOLUME.DATE"]);
+        assert_eq!(headers(HeaderMode::Both)[1], "TR.Close(Curn=EUR)|Close Price");
+        assert_eq!(dedup_headers(vec!["A".to_string(), "A_1".to_string(), "A".to_string()]), vec!["A", "A_1", "A_2"]);
     }
 }
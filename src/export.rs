@@ -0,0 +1,843 @@
+//! Writes downloaded DataFrames to the terminal, CSV, JSON, Parquet and Arrow IPC.
+//!
+//! Downloads written with `write_download` have the same column order whatever the order the
+//! server returned them in:
+//!
+//! 1. the instrument, `RIC` for TimeSeries and `Instrument` for Datagrid
+//! 2. `TIMESTAMP` for TimeSeries
+//! 3. the columns named after a requested field, in the order the fields were requested
+//! 4. the other columns, in the order they appear in the frame
+//!
+//! Column names are the ones the frame has: TimeSeries field names such as `CLOSE`, and Datagrid
+//! names as set by its `HeaderMode`. Dates are written as `%Y-%m-%d` and timestamps as
+//! `%Y-%m-%dT%H:%M:%S%.f` in text formats, and keep their types in Parquet and Arrow IPC.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::{FileWriter, StreamWriter};
+use chrono::prelude::*;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{BrotliLevel, GzipLevel, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use parquet::format::KeyValue;
+use polars::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::utils::{field_string, file_name, EkError};
+
+/// File format a DataFrame is written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Printed table, as shown in the terminal, with as many rows as `POLARS_FMT_MAX_ROWS` allows
+    Table,
+    Csv,
+    Json,
+    Parquet,
+    /// Arrow IPC file, also known as Feather
+    Ipc,
+    /// Arrow IPC stream, which can be read before it is complete
+    IpcStream,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Format::Table => { "table" }
+            Format::Csv => { "csv" }
+            Format::Json => { "json" }
+            Format::Parquet => { "parquet" }
+            Format::Ipc => { "ipc" }
+            Format::IpcStream => { "ipc_stream" }
+        }
+    }
+
+    /// Format matching the extension of `path`, a table when there is no file.
+    ///
+    /// Fails on extensions that are not one of the formats, rather than writing CSV to a file
+    /// named e.g. `out.xlsx`.
+    pub fn guess(path: Option<&Path>) -> Result<Format, EkError> {
+        let extension = path.and_then(|p| p.extension()).and_then(|e| e.to_str());
+        match extension.map(|e| e.to_lowercase()).as_deref() {
+            None | Some("txt") => Ok(Format::Table),
+            Some("csv") => Ok(Format::Csv),
+            Some("json") => Ok(Format::Json),
+            Some("parquet") | Some("pq") => Ok(Format::Parquet),
+            Some("arrow") | Some("feather") | Some("ipc") => Ok(Format::Ipc),
+            Some("arrows") => Ok(Format::IpcStream),
+            Some(e) => Err(EkError::Config(format!(
+                "Cannot tell the format of a .{} file, use --format with table, csv, json, parquet, ipc or ipc_stream", e
+            ))),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = EkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "table" => Ok(Format::Table),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "parquet" => Ok(Format::Parquet),
+            "ipc" | "arrow" | "feather" => Ok(Format::Ipc),
+            "ipc_stream" | "arrows" => Ok(Format::IpcStream),
+            _ => Err(EkError::Config(format!("Unknown output format {}, use table, csv, json, parquet, ipc or ipc_stream", s)))
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// How CSV files are written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    /// Format of `Date` columns, as understood by `chrono`
+    pub date_format: String,
+    /// Format of `Datetime` columns, as understood by `chrono`
+    pub datetime_format: String,
+    /// Text written for missing values
+    pub null_value: String,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            date_format: "%Y-%m-%d".to_string(),
+            datetime_format: "%Y-%m-%dT%H:%M:%S%.f".to_string(),
+            null_value: String::new(),
+        }
+    }
+}
+
+/// Parses a CSV delimiter, a single ASCII character or `tab`.
+pub fn parse_delimiter(s: &str) -> Result<u8, EkError> {
+    match s {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(EkError::Config(format!("Delimiter {} is not a single ASCII character", s)))
+    }
+}
+
+/// Options of every format, each format only reads its own.
+#[derive(Clone, Debug)]
+pub struct ExportOptions {
+    pub csv: CsvOptions,
+    pub parquet: ParquetOptions,
+    /// Rows converted and written at a time, so that a frame is never copied whole
+    pub batch_size: usize,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            csv: CsvOptions::default(),
+            parquet: ParquetOptions::default(),
+            batch_size: 65536,
+        }
+    }
+}
+
+/// Writes `df` to `path`, or to stdout when there is no path, with the default options.
+///
+/// # Arguments
+///
+/// * `df` - The data to write
+/// * `format` - Format to write in, guessed from the extension of `path` when `None`
+/// * `path` - File to create, replaced if it exists
+pub fn write(df: &mut DataFrame, format: Option<Format>, path: Option<&Path>) -> Result<(), EkError> {
+    write_with(df, format, path, &ExportOptions::default(), None)
+}
+
+/// Writes a download with the columns in the order described in the module documentation, and
+/// the request stored in the metadata of Parquet files.
+///
+/// Partitioning in `options` writes Parquet when `format` is `None`, and fails with any other format.
+pub fn write_download(
+    df: &mut DataFrame,
+    format: Option<Format>,
+    path: Option<&Path>,
+    options: &ExportOptions,
+    metadata: &RequestMetadata,
+) -> Result<(), EkError> {
+    let mut df = stable_columns(df, &metadata.fields)?;
+    write_with(&mut df, format, path, options, Some(metadata))
+}
+
+fn write_with(
+    df: &mut DataFrame,
+    format: Option<Format>,
+    path: Option<&Path>,
+    options: &ExportOptions,
+    metadata: Option<&RequestMetadata>,
+) -> Result<(), EkError> {
+    let partitioned = options.parquet.partition != Partition::None;
+    let format = match format {
+        Some(f) => f,
+        // Partitions go to a directory, which usually has no extension to guess from
+        None if partitioned && path.and_then(|p| p.extension()).is_none() => Format::Parquet,
+        None => Format::guess(path)?
+    };
+    if partitioned && format != Format::Parquet {
+        return Err(EkError::Config(format!("Only Parquet can be partitioned, not {}", format)));
+    }
+    match (format, path) {
+        (Format::Parquet, Some(p)) => write_parquet(df, p, &options.parquet, metadata, options.batch_size).map(|_| ()),
+        // Not locked, as the Parquet writer needs a writer it can send to other threads
+        (_, None) => write_stream(df, format, options, metadata, io::BufWriter::new(io::stdout())),
+        (_, Some(p)) => write_stream(df, format, options, metadata, io::BufWriter::new(File::create(p)?))
+    }
+}
+
+pub fn write_to(df: &mut DataFrame, format: Format, options: &ExportOptions, writer: impl io::Write + Send) -> Result<(), EkError> {
+    write_stream(df, format, options, None, writer)
+}
+
+fn write_stream(
+    df: &mut DataFrame,
+    format: Format,
+    options: &ExportOptions,
+    metadata: Option<&RequestMetadata>,
+    mut writer: impl io::Write + Send,
+) -> Result<(), EkError> {
+    let batch_size = options.batch_size.max(1);
+    match format {
+        Format::Table => writeln!(writer, "{}", df)?,
+        Format::Csv => {
+            CsvWriter::new(writer)
+                .has_header(true)
+                .with_delimiter(options.csv.delimiter)
+                .with_date_format(Some(options.csv.date_format.to_owned()))
+                .with_datetime_format(Some(options.csv.datetime_format.to_owned()))
+                .with_null_value(options.csv.null_value.to_owned())
+                .with_batch_size(batch_size)
+                .finish(df)?
+        }
+        Format::Json => JsonWriter::new(writer).with_json_format(JsonFormat::Json).finish(df)?,
+        Format::Parquet => {
+            if options.parquet.partition != Partition::None {
+                return Err(EkError::Config("Partitioned Parquet needs an output directory, it cannot be written to stdout".to_string()));
+            }
+            let props = writer_properties(&options.parquet, metadata)?;
+            let mut parquet = ArrowWriter::try_new(writer, Arc::new(arrow_schema(df)?), Some(props))?;
+            for batch in batches(df, batch_size) {
+                parquet.write(&record_batch(&batch)?)?;
+            }
+            parquet.into_inner()?.flush()?;
+        }
+        Format::Ipc => {
+            let mut ipc = FileWriter::try_new(writer, &arrow_schema(df)?)?;
+            for batch in batches(df, batch_size) {
+                ipc.write(&record_batch(&batch)?)?;
+            }
+            ipc.finish()?;
+        }
+        Format::IpcStream => {
+            let mut ipc = StreamWriter::try_new(writer, &arrow_schema(df)?)?;
+            for batch in batches(df, batch_size) {
+                ipc.write(&record_batch(&batch)?)?;
+            }
+            ipc.finish()?;
+        }
+    }
+    Ok(())
+}
+
+/// Consecutive slices of at most `size` rows, sharing the memory of `df`.
+fn batches(df: &DataFrame, size: usize) -> impl Iterator<Item=DataFrame> + '_ {
+    (0..df.height()).step_by(size.max(1)).map(move |offset| df.slice(offset as i64, size))
+}
+
+/// Reorders the columns of a download as described in the module documentation.
+///
+/// # Arguments
+///
+/// * `df` - A TimeSeries or Datagrid frame
+/// * `fields` - The requested fields, in the order they were requested
+pub fn stable_columns(df: &DataFrame, fields: &[String]) -> Result<DataFrame, EkError> {
+    let names = df.get_column_names();
+    let mut order: Vec<&str> = Vec::with_capacity(names.len());
+    for key in ["RIC", "Instrument", "TIMESTAMP"] {
+        if names.contains(&key) && !order.contains(&key) {
+            order.push(key);
+        }
+    }
+    for field in fields {
+        if let Some(name) = names.iter().find(|n| n.eq_ignore_ascii_case(field)) {
+            if !order.contains(name) {
+                order.push(name);
+            }
+        }
+    }
+    for name in names.iter() {
+        if !order.contains(name) {
+            order.push(name);
+        }
+    }
+    Ok(df.select(order)?)
+}
+
+/// Compression codec of Parquet files, with its level when it takes one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Uncompressed,
+    Snappy,
+    Gzip(u32),
+    Lz4,
+    Zstd(i32),
+    Brotli(u32),
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Zstd(3)
+    }
+}
+
+impl Compression {
+    fn codec(&self) -> Result<parquet::basic::Compression, EkError> {
+        let codec = match self {
+            Compression::Uncompressed => { parquet::basic::Compression::UNCOMPRESSED }
+            Compression::Snappy => { parquet::basic::Compression::SNAPPY }
+            Compression::Gzip(l) => { parquet::basic::Compression::GZIP(GzipLevel::try_new(*l)?) }
+            Compression::Lz4 => { parquet::basic::Compression::LZ4_RAW }
+            Compression::Zstd(l) => { parquet::basic::Compression::ZSTD(ZstdLevel::try_new(*l)?) }
+            Compression::Brotli(l) => { parquet::basic::Compression::BROTLI(BrotliLevel::try_new(*l)?) }
+        };
+        Ok(codec)
+    }
+}
+
+/// Parses a codec name, optionally followed by its level, e.g. `snappy`, `zstd` or `zstd:9`.
+impl FromStr for Compression {
+    type Err = EkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (name, level) = match s.split_once(':') {
+            None => (s.as_str(), None),
+            Some((n, l)) => (n, Some(l))
+        };
+        let level = |default: i64| match level {
+            None => Ok(default),
+            Some(l) => l.parse::<i64>().map_err(|_| EkError::Config(format!("Compression level {} is not a number", l)))
+        };
+        let res = match name {
+            "none" | "uncompressed" => Compression::Uncompressed,
+            "snappy" => Compression::Snappy,
+            "gzip" => Compression::Gzip(level(6)? as u32),
+            "lz4" => Compression::Lz4,
+            "zstd" => Compression::Zstd(level(3)? as i32),
+            "brotli" => Compression::Brotli(level(1)? as u32),
+            _ => return Err(EkError::Config(format!("Unknown compression {}, use none, snappy, gzip, lz4, zstd or brotli", s)))
+        };
+        // Rejects levels the codec does not support
+        res.codec()?;
+        Ok(res)
+    }
+}
+
+/// How a Parquet export is split into files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Partition {
+    /// One file
+    #[default]
+    None,
+    /// One file per instrument, from the `RIC` or `Instrument` column
+    Ric,
+    /// One file per calendar year of the first date column
+    Year,
+}
+
+impl FromStr for Partition {
+    type Err = EkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "none" => Ok(Partition::None),
+            "ric" | "instrument" => Ok(Partition::Ric),
+            "year" => Ok(Partition::Year),
+            _ => Err(EkError::Config(format!("Unknown partitioning {}, use none, ric or year", s)))
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ParquetOptions {
+    pub compression: Compression,
+    pub partition: Partition,
+    /// Rows per row group, the writer's default when not set
+    pub row_group_size: Option<usize>,
+}
+
+/// What was requested to produce an export, stored in the key-value metadata of Parquet files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestMetadata {
+    /// `timeseries` or `datagrid`
+    pub kind: String,
+    pub instruments: Vec<String>,
+    pub fields: Vec<String>,
+    pub interval: Option<String>,
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+    pub parameters: HashMap<String, String>,
+    pub downloaded_at: DateTime<Utc>,
+}
+
+impl RequestMetadata {
+    /// Metadata of a TimeSeries request downloaded now.
+    pub fn timeseries(
+        rics: &[String],
+        fields: &[impl ToString],
+        interval: impl ToString,
+        start_date: NaiveDateTime,
+        end_date: NaiveDateTime,
+    ) -> Self {
+        Self {
+            kind: "timeseries".to_string(),
+            instruments: rics.to_vec(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            interval: Some(interval.to_string()),
+            start_date: Some(start_date.format("%Y-%m-%dT%H:%M:%S").to_string()),
+            end_date: Some(end_date.format("%Y-%m-%dT%H:%M:%S").to_string()),
+            parameters: HashMap::new(),
+            downloaded_at: Utc::now(),
+        }
+    }
+
+    /// Metadata of a Datagrid request downloaded now, `fields` being the value given to `get_datagrid`.
+    pub fn datagrid(instruments: &[String], fields: &Value, parameters: Option<&HashMap<String, String>>) -> Self {
+        let parameters = parameters.cloned().unwrap_or_default();
+        Self {
+            kind: "datagrid".to_string(),
+            instruments: instruments.to_vec(),
+            fields: fields.as_array().map(|f| f.iter().map(field_string).collect()).unwrap_or_default(),
+            interval: parameters.get("Frq").cloned(),
+            start_date: parameters.get("SDate").cloned(),
+            end_date: parameters.get("EDate").cloned(),
+            parameters,
+            downloaded_at: Utc::now(),
+        }
+    }
+
+    /// Entries written to the file, under keys starting with `eikon.`.
+    pub fn key_values(&self) -> Vec<(String, String)> {
+        let mut parameters = self.parameters.iter().collect::<Vec<(&String, &String)>>();
+        parameters.sort();
+        let mut res = vec![
+            ("eikon.kind".to_string(), self.kind.to_owned()),
+            ("eikon.instruments".to_string(), json!(self.instruments).to_string()),
+            ("eikon.fields".to_string(), json!(self.fields).to_string()),
+            ("eikon.downloaded_at".to_string(), self.downloaded_at.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        ];
+        let optional = [("eikon.interval", &self.interval), ("eikon.start_date", &self.start_date), ("eikon.end_date", &self.end_date)];
+        for (k, v) in optional {
+            if let Some(v) = v {
+                res.push((k.to_string(), v.to_owned()));
+            }
+        }
+        if !parameters.is_empty() {
+            let parameters = parameters.into_iter().map(|(k, v)| (k.to_owned(), json!(v))).collect::<serde_json::Map<String, Value>>();
+            res.push(("eikon.parameters".to_string(), Value::Object(parameters).to_string()));
+        }
+        res
+    }
+}
+
+/// Writes `df` as Parquet.
+///
+/// # Arguments
+///
+/// * `df` - The data to write
+/// * `path` - File to create, or directory the partitions are written to, e.g. `RIC=XOM/part.parquet`
+/// * `options` - Compression and partitioning
+/// * `metadata` - Request stored in the key-value metadata of every file
+/// * `batch_size` - Rows converted to Arrow at a time
+///
+/// # Returns
+///
+/// The files written
+pub fn write_parquet(
+    df: &DataFrame,
+    path: &Path,
+    options: &ParquetOptions,
+    metadata: Option<&RequestMetadata>,
+    batch_size: usize,
+) -> Result<Vec<PathBuf>, EkError> {
+    let props = writer_properties(options, metadata)?;
+    let parts = match options.partition {
+        Partition::None => vec![(None, df.to_owned())],
+        p => partitions(df, p)?
+    };
+    let mut res = Vec::with_capacity(parts.len());
+    for (key, part) in parts {
+        let file = match key {
+            None => path.to_path_buf(),
+            Some(k) => {
+                let dir = path.join(k);
+                fs::create_dir_all(&dir)?;
+                dir.join("part.parquet")
+            }
+        };
+        let mut writer = ArrowWriter::try_new(File::create(&file)?, Arc::new(arrow_schema(&part)?), Some(props.to_owned()))?;
+        for batch in batches(&part, batch_size) {
+            writer.write(&record_batch(&batch)?)?;
+        }
+        writer.close()?;
+        res.push(file);
+    }
+    Ok(res)
+}
+
+/// Compression, row group size and request metadata shared by every Parquet file written.
+fn writer_properties(options: &ParquetOptions, metadata: Option<&RequestMetadata>) -> Result<WriterProperties, EkError> {
+    let mut props = WriterProperties::builder()
+        .set_compression(options.compression.codec()?);
+    if let Some(size) = options.row_group_size {
+        props = props.set_max_row_group_size(size);
+    }
+    if let Some(m) = metadata {
+        let kv = m.key_values().into_iter().map(|(k, v)| KeyValue::new(k, v)).collect();
+        props = props.set_key_value_metadata(Some(kv));
+    }
+    Ok(props.build())
+}
+
+/// Splits `df` by instrument or year, in the order the keys first appear.
+///
+/// # Returns
+///
+/// The directory name of each part, e.g. `RIC=XOM` or `year=2023`, with its rows
+fn partitions(df: &DataFrame, partition: Partition) -> Result<Vec<(Option<String>, DataFrame)>, EkError> {
+    let (column, keys) = match partition {
+        Partition::None => return Ok(vec![(None, df.to_owned())]),
+        Partition::Ric => {
+            let column = ["RIC", "Instrument"].into_iter()
+                .find(|c| df.column(c).is_ok())
+                .ok_or(EkError::Config("No RIC or Instrument column to partition by".to_string()))?;
+            let keys = df.column(column)?.cast(&DataType::Utf8)?
+                .utf8()?
+                .into_iter()
+                .map(|v| v.map(|v| v.to_string()))
+                .collect::<Vec<Option<String>>>();
+            (column.to_string(), keys)
+        }
+        Partition::Year => {
+            let series = df.get_columns().iter()
+                .find(|s| matches!(s.dtype(), DataType::Date | DataType::Datetime(_, _)))
+                .ok_or(EkError::Config("No date column to partition by".to_string()))?;
+            // Dates are cast to midnight, so that both are read the same way
+            let keys = series.cast(&DataType::Datetime(TimeUnit::Milliseconds, None))?
+                .to_physical_repr()
+                .i64()?
+                .into_iter()
+                .map(|v| v.and_then(DateTime::from_timestamp_millis).map(|d| d.year().to_string()))
+                .collect::<Vec<Option<String>>>();
+            ("year".to_string(), keys)
+        }
+    };
+
+    let mut order: Vec<Option<String>> = Vec::new();
+    let mut rows: HashMap<Option<String>, Vec<IdxSize>> = HashMap::new();
+    for (i, key) in keys.into_iter().enumerate() {
+        if !rows.contains_key(&key) {
+            order.push(key.to_owned());
+        }
+        rows.entry(key).or_default().push(i as IdxSize);
+    }
+    let mut res = Vec::with_capacity(order.len());
+    for key in order {
+        let idx = IdxCa::from_vec("", rows.remove(&key).unwrap_or_default());
+        let name = format!("{}={}", column, partition_name(key.as_deref()));
+        res.push((Some(name), df.take(&idx)?));
+    }
+    Ok(res)
+}
+
+/// Directory name of a partition value, escaped with `file_name`.
+fn partition_name(key: Option<&str>) -> String {
+    match key {
+        // Hive's name for missing partition values
+        None => "__HIVE_DEFAULT_PARTITION__".to_string(),
+        Some(k) => file_name(k)
+    }
+}
+
+fn arrow_type(dtype: &DataType) -> arrow_schema::DataType {
+    match dtype {
+        DataType::Boolean => arrow_schema::DataType::Boolean,
+        DataType::Int8 => arrow_schema::DataType::Int8,
+        DataType::Int16 => arrow_schema::DataType::Int16,
+        DataType::Int32 => arrow_schema::DataType::Int32,
+        DataType::Int64 => arrow_schema::DataType::Int64,
+        DataType::UInt8 => arrow_schema::DataType::UInt8,
+        DataType::UInt16 => arrow_schema::DataType::UInt16,
+        DataType::UInt32 => arrow_schema::DataType::UInt32,
+        DataType::UInt64 => arrow_schema::DataType::UInt64,
+        DataType::Float32 => arrow_schema::DataType::Float32,
+        DataType::Float64 => arrow_schema::DataType::Float64,
+        DataType::Date => arrow_schema::DataType::Date32,
+        DataType::Datetime(unit, tz) => {
+            let unit = match unit {
+                TimeUnit::Milliseconds => arrow_schema::TimeUnit::Millisecond,
+                TimeUnit::Microseconds => arrow_schema::TimeUnit::Microsecond,
+                TimeUnit::Nanoseconds => arrow_schema::TimeUnit::Nanosecond,
+            };
+            arrow_schema::DataType::Timestamp(unit, tz.as_ref().map(|t| t.as_str().into()))
+        }
+        DataType::Null => arrow_schema::DataType::Null,
+        // Lists, structs and the other types are written as text
+        _ => arrow_schema::DataType::Utf8,
+    }
+}
+
+fn arrow_schema(df: &DataFrame) -> Result<arrow_schema::Schema, EkError> {
+    let fields = df.get_columns().iter()
+        .map(|s| arrow_schema::Field::new(s.name(), arrow_type(s.dtype()), true))
+        .collect::<Vec<arrow_schema::Field>>();
+    Ok(arrow_schema::Schema::new(fields))
+}
+
+/// Converts a column to the Arrow array `arrow_type` gives for it.
+fn arrow_array(s: &Series) -> Result<ArrayRef, EkError> {
+    use arrow_array::*;
+
+    let array: ArrayRef = match arrow_type(s.dtype()) {
+        arrow_schema::DataType::Boolean => Arc::new(s.bool()?.into_iter().collect::<BooleanArray>()),
+        arrow_schema::DataType::Int8 => Arc::new(s.i8()?.into_iter().collect::<Int8Array>()),
+        arrow_schema::DataType::Int16 => Arc::new(s.i16()?.into_iter().collect::<Int16Array>()),
+        arrow_schema::DataType::Int32 => Arc::new(s.i32()?.into_iter().collect::<Int32Array>()),
+        arrow_schema::DataType::Int64 => Arc::new(s.i64()?.into_iter().collect::<Int64Array>()),
+        arrow_schema::DataType::UInt8 => Arc::new(s.u8()?.into_iter().collect::<UInt8Array>()),
+        arrow_schema::DataType::UInt16 => Arc::new(s.u16()?.into_iter().collect::<UInt16Array>()),
+        arrow_schema::DataType::UInt32 => Arc::new(s.u32()?.into_iter().collect::<UInt32Array>()),
+        arrow_schema::DataType::UInt64 => Arc::new(s.u64()?.into_iter().collect::<UInt64Array>()),
+        arrow_schema::DataType::Float32 => Arc::new(s.f32()?.into_iter().collect::<Float32Array>()),
+        arrow_schema::DataType::Float64 => Arc::new(s.f64()?.into_iter().collect::<Float64Array>()),
+        arrow_schema::DataType::Date32 => Arc::new(s.to_physical_repr().i32()?.into_iter().collect::<Date32Array>()),
+        arrow_schema::DataType::Timestamp(unit, tz) => {
+            let physical = s.to_physical_repr();
+            let values = physical.i64()?.into_iter();
+            let array: ArrayRef = match (unit, tz) {
+                (arrow_schema::TimeUnit::Millisecond, None) => Arc::new(values.collect::<TimestampMillisecondArray>()),
+                (arrow_schema::TimeUnit::Millisecond, Some(tz)) => Arc::new(values.collect::<TimestampMillisecondArray>().with_timezone(tz)),
+                (arrow_schema::TimeUnit::Microsecond, None) => Arc::new(values.collect::<TimestampMicrosecondArray>()),
+                (arrow_schema::TimeUnit::Microsecond, Some(tz)) => Arc::new(values.collect::<TimestampMicrosecondArray>().with_timezone(tz)),
+                (_, None) => Arc::new(values.collect::<TimestampNanosecondArray>()),
+                (_, Some(tz)) => Arc::new(values.collect::<TimestampNanosecondArray>().with_timezone(tz)),
+            };
+            array
+        }
+        arrow_schema::DataType::Null => Arc::new(NullArray::new(s.len())),
+        _ => Arc::new(s.cast(&DataType::Utf8)?.utf8()?.into_iter().collect::<StringArray>()),
+    };
+    Ok(array)
+}
+
+fn record_batch(df: &DataFrame) -> Result<RecordBatch, EkError> {
+    let columns = df.get_columns().iter()
+        .map(arrow_array)
+        .collect::<Result<Vec<ArrayRef>, EkError>>()?;
+    Ok(RecordBatch::try_new(Arc::new(arrow_schema(df)?), columns)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        assert_eq!(Format::guess(Some(Path::new("prices.CSV"))).unwrap(), Format::Csv);
+        assert_eq!(Format::guess(Some(Path::new("prices.json"))).unwrap(), Format::Json);
+        assert_eq!(Format::guess(None).unwrap(), Format::Table);
+        assert_eq!(Format::guess(Some(Path::new("prices.parquet"))).unwrap(), Format::Parquet);
+        assert!(matches!(Format::guess(Some(Path::new("out.xlsx"))), Err(EkError::Config(_))));
+        assert!("xlsx".parse::<Format>().is_err());
+
+        let mut df = df!("RIC" => &["XOM", "GME"], "CLOSE" => &[1.5, 2.0]).unwrap();
+        let mut out = Vec::new();
+        write_to(&mut df, Format::Csv, &ExportOptions::default(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "RIC,CLOSE\nXOM,1.5\nGME,2.0\n");
+    }
+
+    #[test]
+    fn test_csv_options() {
+        let day = |d| NaiveDate::from_ymd_opt(2023, 1, d).unwrap();
+        let mut df = df!(
+            "DATE" => &[day(3), day(4)],
+            "TIMESTAMP" => &[day(3).and_hms_opt(9, 30, 0).unwrap(), day(4).and_hms_milli_opt(16, 0, 0, 250).unwrap()],
+            "CLOSE" => &[Some(1.5), None]
+        ).unwrap();
+        let mut options = ExportOptions::default();
+        options.csv.delimiter = parse_delimiter("tab").unwrap();
+        options.csv.null_value = "NA".to_string();
+        let mut out = Vec::new();
+        write_to(&mut df, Format::Csv, &options, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "DATE\tTIMESTAMP\tCLOSE\n2023-01-03\t2023-01-03T09:30:00\t1.5\n2023-01-04\t2023-01-04T16:00:00.250\tNA\n"
+        );
+
+        options.csv.delimiter = parse_delimiter(";").unwrap();
+        options.csv.date_format = "%d/%m/%Y".to_string();
+        options.csv.datetime_format = "%Y-%m-%d %H:%M".to_string();
+        let mut out = Vec::new();
+        write_to(&mut df.head(Some(1)), Format::Csv, &options, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "DATE;TIMESTAMP;CLOSE\n03/01/2023;2023-01-03 09:30;1.5\n");
+        assert!(parse_delimiter(";;").is_err());
+    }
+
+    #[test]
+    fn test_write_ipc() {
+        use arrow_ipc::reader::{FileReader, StreamReader};
+
+        assert_eq!(Format::guess(Some(Path::new("prices.feather"))).unwrap(), Format::Ipc);
+        assert_eq!(Format::guess(Some(Path::new("prices.arrows"))).unwrap(), Format::IpcStream);
+        let mut df = df!(
+            "CLOSE" => &[Some(1.5), None, Some(3.0)],
+            "TIMESTAMP" => &[1i64, 2, 3],
+            "RIC" => &["XOM", "XOM", "GME"]
+        ).unwrap();
+        let options = ExportOptions { batch_size: 2, ..ExportOptions::default() };
+
+        let mut out = Vec::new();
+        write_to(&mut df, Format::Ipc, &options, &mut out).unwrap();
+        let reader = FileReader::try_new(io::Cursor::new(out), None).unwrap();
+        assert_eq!(reader.num_batches(), 2);
+        let batches = reader.collect::<Result<Vec<RecordBatch>, _>>().unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+        assert_eq!(batches[0].schema().field(0).name(), "CLOSE");
+        assert_eq!(batches[0].column(0).null_count(), 1);
+
+        let mut out = Vec::new();
+        write_to(&mut df, Format::IpcStream, &options, &mut out).unwrap();
+        let reader = StreamReader::try_new(io::Cursor::new(out), None).unwrap();
+        assert_eq!(reader.map(|b| b.unwrap().num_rows()).collect::<Vec<usize>>(), vec![2, 1]);
+
+        // Numbers keep their width and sign in Arrow IPC and Parquet
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+        let types = [DataType::Int32, DataType::Int64, DataType::UInt32, DataType::UInt64, DataType::Float32];
+        let mut df = DataFrame::new(types.iter()
+            .map(|t| Series::new(&t.to_string(), &[1i64, 2]).cast(t).unwrap())
+            .collect()).unwrap();
+        let expected = [
+            arrow_schema::DataType::Int32, arrow_schema::DataType::Int64, arrow_schema::DataType::UInt32,
+            arrow_schema::DataType::UInt64, arrow_schema::DataType::Float32,
+        ];
+        let mut out = Vec::new();
+        write_to(&mut df, Format::Ipc, &options, &mut out).unwrap();
+        let schema = FileReader::try_new(io::Cursor::new(out), None).unwrap().schema();
+        assert_eq!(schema.fields().iter().map(|f| f.data_type().to_owned()).collect::<Vec<_>>(), expected);
+
+        let mut file = tempfile::tempfile().unwrap();
+        write_to(&mut df, Format::Parquet, &options, &mut file).unwrap();
+        let batches = ParquetRecordBatchReaderBuilder::try_new(file).unwrap().build().unwrap()
+            .collect::<Result<Vec<RecordBatch>, _>>().unwrap();
+        assert_eq!(batches[0].schema().fields().iter().map(|f| f.data_type().to_owned()).collect::<Vec<_>>(), expected);
+        assert_eq!(batches[0].column(2).as_any().downcast_ref::<arrow_array::UInt32Array>().unwrap().value(1), 2);
+    }
+
+    #[test]
+    fn test_stable_columns() {
+        let df = df!(
+            "VOLUME" => &[10i64],
+            "close" => &[1.5],
+            "TIMESTAMP" => &[1i64],
+            "OPEN" => &[1.0],
+            "RIC" => &["XOM"]
+        ).unwrap();
+        let df = stable_columns(&df, &["CLOSE".to_string(), "VOLUME".to_string(), "HIGH".to_string()]).unwrap();
+        assert_eq!(df.get_column_names(), vec!["RIC", "TIMESTAMP", "close", "VOLUME", "OPEN"]);
+
+        let df = df!("TR.CLOSE" => &[1.5], "Instrument" => &["XOM"], "TR.OPEN" => &[1.0]).unwrap();
+        let df = stable_columns(&df, &["TR.OPEN".to_string(), "TR.CLOSE".to_string()]).unwrap();
+        assert_eq!(df.get_column_names(), vec!["Instrument", "TR.OPEN", "TR.CLOSE"]);
+    }
+
+    #[test]
+    fn test_write_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let day = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let df = df!(
+            "TIMESTAMP" => &[day(2022, 12, 30), day(2023, 1, 3), day(2023, 1, 3)],
+            "CLOSE" => &[Some(1.5), None, Some(3.0)],
+            "VOLUME" => &[10i64, 20, 30],
+            "RIC" => &["XOM", "XOM", "EUR/USD"]
+        ).unwrap();
+        let metadata = RequestMetadata::timeseries(&["XOM".to_string(), "EUR/USD".to_string()], &["CLOSE", "VOLUME"], "daily", day(2022, 12, 1), day(2023, 1, 4));
+        let read = |path: &Path| SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+
+        let options = ParquetOptions { compression: "snappy".parse().unwrap(), ..ParquetOptions::default() };
+        let files = write_parquet(&df, &dir.join("prices.parquet"), &options, Some(&metadata), 2).unwrap();
+        let reader = read(&files[0]);
+        let meta = reader.metadata().file_metadata();
+        assert_eq!(meta.num_rows(), 3);
+        assert_eq!(meta.schema_descr().column(0).name(), "TIMESTAMP");
+        let kv = meta.key_value_metadata().unwrap().iter()
+            .filter_map(|kv| Some((kv.key.as_str(), kv.value.as_deref()?)))
+            .collect::<HashMap<&str, &str>>();
+        assert_eq!(kv["eikon.fields"], r#"["CLOSE","VOLUME"]"#);
+        assert_eq!(kv["eikon.interval"], "daily");
+        assert_eq!(kv["eikon.start_date"], "2022-12-01T00:00:00");
+        assert!(kv.contains_key("eikon.downloaded_at"));
+
+        let options = ParquetOptions { partition: Partition::Ric, ..ParquetOptions::default() };
+        let files = write_parquet(&df, &dir.join("by_ric"), &options, None, 2).unwrap();
+        assert_eq!(files, vec![dir.join("by_ric/RIC=XOM/part.parquet"), dir.join("by_ric/RIC=EUR%2FUSD/part.parquet")]);
+        assert_eq!(read(&files[0]).metadata().file_metadata().num_rows(), 2);
+
+        let options = ParquetOptions { partition: Partition::Year, ..ParquetOptions::default() };
+        let files = write_parquet(&df, &dir.join("by_year"), &options, None, 2).unwrap();
+        assert_eq!(files, vec![dir.join("by_year/year=2022/part.parquet"), dir.join("by_year/year=2023/part.parquet")]);
+        assert_eq!(read(&files[1]).metadata().file_metadata().num_rows(), 2);
+
+        // Streamed Parquet keeps the compression and metadata, and cannot be partitioned
+        let options = ExportOptions {
+            parquet: ParquetOptions { compression: "gzip".parse().unwrap(), ..ParquetOptions::default() },
+            ..ExportOptions::default()
+        };
+        let stream = dir.join("stream.parquet");
+        write_stream(&mut df.clone(), Format::Parquet, &options, Some(&metadata), File::create(&stream).unwrap()).unwrap();
+        let reader = read(&stream);
+        assert_eq!(reader.metadata().row_group(0).column(0).compression(), parquet::basic::Compression::GZIP(GzipLevel::default()));
+        assert!(reader.metadata().file_metadata().key_value_metadata().unwrap().iter().any(|kv| kv.key == "eikon.fields"));
+        let options = ExportOptions {
+            parquet: ParquetOptions { partition: Partition::Ric, ..ParquetOptions::default() },
+            ..ExportOptions::default()
+        };
+        assert!(matches!(write_stream(&mut df.clone(), Format::Parquet, &options, None, Vec::new()), Err(EkError::Config(_))));
+
+        // Partitioning writes Parquet to a directory without an extension, and no other format
+        write_with(&mut df.clone(), None, Some(&dir.join("out")), &options, None).unwrap();
+        assert!(dir.join("out/RIC=XOM/part.parquet").exists());
+        assert!(matches!(write_with(&mut df.clone(), Some(Format::Csv), Some(&dir.join("csv")), &options, None), Err(EkError::Config(_))));
+        assert!(matches!(write_with(&mut df.clone(), None, Some(&dir.join("out.csv")), &options, None), Err(EkError::Config(_))));
+        assert!(!dir.join("csv").exists() && !dir.join("out.csv").exists());
+
+        let big = df!("COUNT" => &[u64::MAX, 1]).unwrap();
+        let batch = record_batch(&big).unwrap();
+        assert_eq!(batch.schema().field(0).data_type(), &arrow_schema::DataType::UInt64);
+        assert_eq!(batch.column(0).as_any().downcast_ref::<arrow_array::UInt64Array>().unwrap().value(0), u64::MAX);
+
+        assert_eq!("zstd:9".parse::<Compression>().unwrap(), Compression::Zstd(9));
+        assert!("zstd:99".parse::<Compression>().is_err());
+        assert!("lzo".parse::<Compression>().is_err());
+    }
+}
@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::prelude::*;
+use chrono::Duration;
+use polars::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use log::info;
+use crate::cache::Cache;
+use crate::calendar::{Nyse, Weekdays};
+use crate::config::{Config, read_list};
+use crate::connection::Connection;
+use crate::datagrid::{CellErrors, Datagrid, HeaderMode, MixedTypes};
+use crate::export::{parse_delimiter, write_download, ExportOptions, Format, ParquetOptions, Partition, RequestMetadata};
+use crate::report::{Partial, Report};
+use crate::timeseries::{Interval, TimeSeries, TsField};
+use crate::utils::{clean_string, parse_field, parse_timestamp, EkError};
+
+/// Downloads described in a TOML or YAML file, such as
+///
+/// ```toml
+/// [[requests]]
+/// kind = "timeseries"
+/// name = "prices"
+/// instruments_file = "universe.csv"
+/// instruments_column = "RIC"
+/// fields = ["CLOSE", "VOLUME"]
+/// interval = "daily"
+/// start = "-30d"
+/// cache = "history"
+/// output = "prices.csv"
+/// delimiter = ";"
+///
+/// [[requests]]
+/// kind = "datagrid"
+/// instruments = ["XOM", "GME"]
+/// fields = ["TR.GrossProfit(Curn=EUR)", { name = "TR.CLOSE", parameters = { Scale = 6 } }]
+/// parameters = { SDate = "2020-01-01", EDate = "2020-12-31", Frq = "M" }
+/// header_mode = "field_code"
+/// output = "fundamentals"
+/// format = "parquet"
+/// compression = "zstd:9"
+/// partition_by = "ric"
+/// ```
+///
+/// Relative paths are resolved against the directory of the job file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Job {
+    pub requests: Vec<JobRequest>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum JobRequest {
+    Timeseries(TimeseriesJob),
+    Datagrid(DatagridJob),
+}
+
+/// Instruments given in the job file, read from a file, or both.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct InstrumentList {
+    #[serde(default)]
+    pub instruments: Vec<String>,
+    /// Text file with one instrument per line, or CSV file when it ends in `.csv`
+    pub instruments_file: Option<PathBuf>,
+    /// Column of the CSV file holding the instruments, the first one by default
+    pub instruments_column: Option<String>,
+}
+
+/// Where the result of a request is written.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Target {
+    /// File to write, stdout when not set
+    pub output: Option<PathBuf>,
+    /// Format of the file, guessed from its extension when not set
+    pub format: Option<Format>,
+    /// Parquet codec with its level, e.g. `zstd:9`
+    pub compression: Option<String>,
+    /// Parquet files per `ric` or per `year`, `output` then being a directory. Only valid with
+    /// the `parquet` format, which is the default when `output` has no extension
+    pub partition_by: Option<Partition>,
+    /// CSV delimiter, a single character or `tab`
+    pub delimiter: Option<String>,
+    /// `chrono` format of CSV dates, `%Y-%m-%d` by default
+    pub date_format: Option<String>,
+    /// `chrono` format of CSV timestamps, `%Y-%m-%dT%H:%M:%S%.f` by default
+    pub datetime_format: Option<String>,
+}
+
+impl Target {
+    fn export_options(&self) -> Result<ExportOptions, EkError> {
+        let mut options = ExportOptions {
+            parquet: ParquetOptions {
+                compression: match &self.compression {
+                    None => Default::default(),
+                    Some(c) => c.parse()?
+                },
+                partition: self.partition_by.unwrap_or_default(),
+                ..ParquetOptions::default()
+            },
+            ..ExportOptions::default()
+        };
+        if let Some(d) = &self.delimiter {
+            options.csv.delimiter = parse_delimiter(d)?;
+        }
+        if let Some(f) = &self.date_format {
+            options.csv.date_format = f.to_owned();
+        }
+        if let Some(f) = &self.datetime_format {
+            options.csv.datetime_format = f.to_owned();
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TimeseriesJob {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub instruments: InstrumentList,
+    /// Every field when empty
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Daily when not set
+    pub interval: Option<String>,
+    /// Date, or `now`, `today` or a number of days before today such as `-30d`
+    pub start: String,
+    /// Same forms as `start`, now when not set
+    pub end: Option<String>,
+    /// `weekdays` or `nyse`
+    pub calendar: Option<String>,
+    /// Directory of the local cache, only what it does not hold is downloaded
+    pub cache: Option<PathBuf>,
+    /// Downloads the window again and replaces what the cache holds for it
+    #[serde(default)]
+    pub refresh: bool,
+    #[serde(flatten)]
+    pub target: Target,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DatagridJob {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub instruments: InstrumentList,
+    /// Field codes such as `TR.CLOSE(Curn=EUR)`, or tables with a `name` and `parameters`
+    pub fields: Vec<Value>,
+    #[serde(default)]
+    pub parameters: HashMap<String, Value>,
+    pub header_mode: Option<HeaderMode>,
+    pub mixed_types: Option<MixedTypes>,
+    pub cell_errors: Option<CellErrors>,
+    #[serde(flatten)]
+    pub target: Target,
+}
+
+impl Job {
+    /// Reads a job file, as YAML when it ends in `.yaml` or `.yml` and as TOML otherwise.
+    pub fn load(path: &Path) -> Result<Job, EkError> {
+        let content = fs::read_to_string(path)?;
+        let yaml = matches!(path.extension().and_then(|e| e.to_str()), Some("yaml") | Some("yml"));
+        let mut job = Job::parse(&content, yaml)?;
+        if let Some(base) = path.parent() {
+            job.resolve(base);
+        }
+        Ok(job)
+    }
+
+    pub fn parse(s: &str, yaml: bool) -> Result<Job, EkError> {
+        let res = match yaml {
+            true => serde_yaml::from_str(s).map_err(|e| e.to_string()),
+            false => toml::from_str(s).map_err(|e| e.to_string()),
+        };
+        res.map_err(EkError::Config)
+    }
+
+    /// Makes the relative paths of the job relative to `base`.
+    fn resolve(&mut self, base: &Path) {
+        let join = |p: &mut Option<PathBuf>| {
+            if let Some(path) = p.as_mut() {
+                if path.is_relative() {
+                    *path = base.join(&path);
+                }
+            }
+        };
+        for request in self.requests.iter_mut() {
+            let (instruments, target) = match request {
+                JobRequest::Timeseries(r) => (&mut r.instruments, &mut r.target),
+                JobRequest::Datagrid(r) => (&mut r.instruments, &mut r.target),
+            };
+            join(&mut instruments.instruments_file);
+            join(&mut target.output);
+            if let JobRequest::Timeseries(r) = request {
+                join(&mut r.cache);
+            }
+        }
+    }
+}
+
+impl JobRequest {
+    /// Name given in the job file, or the kind and position of the request.
+    pub fn name(&self, index: usize) -> String {
+        let (kind, name) = match self {
+            JobRequest::Timeseries(r) => ("timeseries", &r.name),
+            JobRequest::Datagrid(r) => ("datagrid", &r.name),
+        };
+        name.to_owned().unwrap_or(format!("{} #{}", kind, index + 1))
+    }
+}
+
+impl InstrumentList {
+    pub fn read(&self) -> Result<Vec<String>, EkError> {
+        let mut res = self.instruments.to_owned();
+        match &self.instruments_file {
+            None => {}
+            Some(p) if p.extension().map(|e| e.eq_ignore_ascii_case("csv")).unwrap_or_default() => {
+                res.extend(read_csv_column(p, self.instruments_column.as_deref())?)
+            }
+            Some(p) => res.extend(read_list(p)?),
+        }
+        if res.is_empty() {
+            return Err(EkError::Config("No instruments, set instruments or instruments_file".to_string()));
+        }
+        Ok(res)
+    }
+}
+
+/// Non-empty values of a column of a CSV file with a header row.
+fn read_csv_column(path: &Path, column: Option<&str>) -> Result<Vec<String>, EkError> {
+    let df = CsvReader::from_path(path)?
+        .has_header(true)
+        .finish()?;
+    let series = match column {
+        Some(c) => df.column(c)?,
+        None => match df.get_columns().first() {
+            None => return Ok(Vec::new()),
+            Some(s) => s
+        }
+    };
+    let values = series.cast(&DataType::Utf8)?;
+    Ok(values.utf8()?
+        .into_iter()
+        .flatten()
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect())
+}
+
+/// Parses a date of a job file, which may be relative to `now`.
+fn parse_date(s: &str, now: NaiveDateTime) -> Result<NaiveDateTime, EkError> {
+    let s = s.trim();
+    let today = now.date().and_hms_opt(0, 0, 0).unwrap_or(now);
+    let days_ago = s.strip_prefix('-')
+        .and_then(|d| d.strip_suffix('d'))
+        .and_then(|d| d.parse::<i64>().ok());
+    match (s, days_ago) {
+        ("now", _) => Ok(now),
+        ("today", _) => Ok(today),
+        (_, Some(d)) => Ok(today - Duration::days(d)),
+        _ => parse_timestamp(s).ok_or(EkError::Config(format!("{} is not a date", s)))
+    }
+}
+
+/// Arguments of a TimeSeries call.
+struct TimeseriesCall {
+    rics: Vec<String>,
+    fields: Vec<TsField>,
+    interval: Interval,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+}
+
+impl TimeseriesJob {
+    fn prepare(&self, now: NaiveDateTime) -> Result<TimeseriesCall, EkError> {
+        let mut fields = self.fields.iter().map(|f| TsField::from(f.as_str())).collect::<Vec<TsField>>();
+        if fields.is_empty() {
+            fields.push(TsField::All);
+        }
+        Ok(TimeseriesCall {
+            rics: self.instruments.read()?,
+            fields,
+            interval: match &self.interval {
+                None => Interval::Daily,
+                Some(i) => i.parse()?
+            },
+            start: parse_date(&self.start, now)?,
+            end: match &self.end {
+                None => now,
+                Some(e) => parse_date(e, now)?
+            },
+        })
+    }
+}
+
+impl DatagridJob {
+    /// Fields in the form `get_datagrid` takes, and the request parameters as strings.
+    fn prepare(&self) -> Result<(Value, HashMap<String, String>), EkError> {
+        let mut fields = Vec::with_capacity(self.fields.len());
+        for f in self.fields.iter() {
+            let field = match f {
+                Value::String(s) => parse_field(s)?,
+                Value::Object(o) if o.contains_key("name") => {
+                    let parameters = o.get("parameters")
+                        .and_then(|p| p.as_object())
+                        .map(|p| p.iter().map(|(k, v)| (k.to_owned(), json!(clean_string(v.to_string())))).collect())
+                        .unwrap_or(serde_json::Map::new());
+                    match parameters.is_empty() {
+                        true => json!({"name": o["name"]}),
+                        false => json!({"name": o["name"], "parameters": parameters})
+                    }
+                }
+                f => return Err(EkError::Config(format!("Field {} has no name", f)))
+            };
+            fields.push(field);
+        }
+        let parameters = self.parameters.iter()
+            .map(|(k, v)| (k.to_owned(), clean_string(v.to_string())))
+            .collect();
+        Ok((Value::Array(fields), parameters))
+    }
+}
+
+/// Result of one request of a job.
+#[derive(Debug)]
+pub struct Outcome {
+    pub name: String,
+    /// Rows written
+    pub rows: usize,
+    pub output: Option<PathBuf>,
+    /// What could not be downloaded
+    pub report: Report,
+    /// Why the request could not run at all
+    pub error: Option<EkError>,
+}
+
+impl Outcome {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && self.report.chunks.is_empty() && self.report.rics.is_empty()
+    }
+}
+
+/// Runs the requests of job files, connecting once per kind of request.
+pub struct Runner {
+    config: Config,
+    connection: Option<Connection>,
+    timeseries: Option<TimeSeries>,
+    datagrid: Option<Datagrid>,
+}
+
+impl Runner {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            connection: None,
+            timeseries: None,
+            datagrid: None,
+        }
+    }
+
+    /// Connection shared by every request of the runner, so that they use one rate limiter.
+    fn connection(&mut self) -> Result<Connection, EkError> {
+        let connection = match self.connection.as_ref() {
+            Some(c) => c,
+            None => self.connection.insert(self.config.connect()?)
+        };
+        Ok(connection.clone())
+    }
+
+    /// Runs every request of `job` in order, a request failing does not stop the ones after it.
+    pub fn run(&mut self, job: &Job) -> Vec<Outcome> {
+        let mut res = Vec::with_capacity(job.requests.len());
+        for (i, request) in job.requests.iter().enumerate() {
+            let name = request.name(i);
+            info!("Running {}", name);
+            let (output, partial) = match request {
+                JobRequest::Timeseries(r) => (&r.target.output, self.run_timeseries(r)),
+                JobRequest::Datagrid(r) => (&r.target.output, self.run_datagrid(r)),
+            };
+            let outcome = match partial {
+                Err(e) => Outcome { name, rows: 0, output: None, report: Report::default(), error: Some(e) },
+                Ok((rows, report)) => Outcome { name, rows, output: output.to_owned(), report, error: None },
+            };
+            res.push(outcome);
+        }
+        res
+    }
+
+    fn run_timeseries(&mut self, request: &TimeseriesJob) -> Result<(usize, Report), EkError> {
+        let call = request.prepare(Utc::now().naive_utc())?;
+        let ts = match self.timeseries.as_mut() {
+            Some(ts) => ts,
+            None => {
+                let connection = self.connection()?;
+                self.timeseries.insert(TimeSeries::new(connection))
+            }
+        };
+        match request.calendar.as_deref().map(|c| c.to_lowercase()).as_deref() {
+            None | Some("weekdays") => ts.set_calendar(Weekdays),
+            Some("nyse") => ts.set_calendar(Nyse),
+            Some(c) => return Err(EkError::Config(format!("Unknown calendar {}, use weekdays or nyse", c)))
+        }
+        ts.set_cache(request.cache.as_ref().map(|dir| {
+            let mut cache = Cache::new(dir);
+            cache.set_refresh(request.refresh);
+            cache
+        }));
+        let options = request.target.export_options()?;
+        let metadata = RequestMetadata::timeseries(&call.rics, &call.fields, call.interval, call.start, call.end);
+        let res = ts.get_timeseries_partial(call.rics, call.fields, call.interval, call.start, call.end)?;
+        write_partial(res, &request.target, &options, &metadata)
+    }
+
+    fn run_datagrid(&mut self, request: &DatagridJob) -> Result<(usize, Report), EkError> {
+        let (fields, parameters) = request.prepare()?;
+        let instruments = request.instruments.read()?;
+        let dg = match self.datagrid.as_mut() {
+            Some(dg) => dg,
+            None => {
+                let connection = self.connection()?;
+                self.datagrid.insert(Datagrid::new(connection))
+            }
+        };
+        dg.set_header_mode(request.header_mode.unwrap_or_default());
+        dg.set_mixed_types(request.mixed_types.unwrap_or_default());
+        dg.set_cell_errors(request.cell_errors.unwrap_or_default());
+        let parameters = match parameters.is_empty() {
+            true => None,
+            false => Some(parameters)
+        };
+        let options = request.target.export_options()?;
+        let metadata = RequestMetadata::datagrid(&instruments, &fields, parameters.as_ref());
+        let res = dg.get_datagrid_partial(instruments, fields, parameters)?;
+        write_partial(res, &request.target, &options, &metadata)
+    }
+}
+
+fn write_partial(
+    res: Partial<DataFrame>,
+    target: &Target,
+    options: &ExportOptions,
+    metadata: &RequestMetadata,
+) -> Result<(usize, Report), EkError> {
+    let rows = match res.data {
+        None => 0,
+        Some(mut df) => {
+            write_download(&mut df, target.format, target.output.as_deref(), options, metadata)?;
+            df.height()
+        }
+    };
+    Ok((rows, res.report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::Compression;
+
+    #[test]
+    fn test_parse_job() {
+        let toml = r#"
+            [[requests]]
+            kind = "timeseries"
+            instruments = ["XOM"]
+            fields = ["CLOSE"]
+            interval = "5minutes"
+            start = "-7d"
+            cache = "history"
+            output = "prices.csv"
+            delimiter = ";"
+
+            [[requests]]
+            kind = "datagrid"
+            name = "fundamentals"
+            instruments_file = "universe.txt"
+            fields = ["TR.GrossProfit(Curn=EUR)", { name = "TR.CLOSE", parameters = { Scale = 6 } }]
+            parameters = { SDate = "2020-01-01", Frq = "M" }
+            header_mode = "field_code"
+            output = "fundamentals"
+            format = "parquet"
+            compression = "gzip:9"
+            partition_by = "year"
+        "#;
+        let mut job = Job::parse(toml, false).unwrap();
+        job.resolve(Path::new("/jobs"));
+        assert_eq!(job.requests[0].name(0), "timeseries #1");
+        assert_eq!(job.requests[1].name(1), "fundamentals");
+
+        let now = NaiveDate::from_ymd_opt(2023, 3, 10).unwrap().and_hms_opt(18, 30, 0).unwrap();
+        match &job.requests[0] {
+            JobRequest::Timeseries(r) => {
+                assert_eq!(r.target.output, Some(PathBuf::from("/jobs/prices.csv")));
+                assert_eq!(r.cache, Some(PathBuf::from("/jobs/history")));
+                assert!(!r.refresh);
+                assert_eq!(r.target.export_options().unwrap().csv.delimiter, b';');
+                let call = r.prepare(now).unwrap();
+                assert_eq!(call.interval, Interval::FiveMinutes);
+                assert_eq!(call.fields, vec![TsField::Close]);
+                assert_eq!(call.start, NaiveDate::from_ymd_opt(2023, 3, 3).unwrap().and_hms_opt(0, 0, 0).unwrap());
+                assert_eq!(call.end, now);
+            }
+            r => panic!("Expected a timeseries request, got {:?}", r),
+        }
+        match &job.requests[1] {
+            JobRequest::Datagrid(r) => {
+                assert_eq!(r.instruments.instruments_file, Some(PathBuf::from("/jobs/universe.txt")));
+                assert_eq!(r.header_mode, Some(HeaderMode::FieldCode));
+                let (fields, parameters) = r.prepare().unwrap();
+                assert_eq!(fields, json!([
+                    {"name": "TR.GrossProfit", "parameters": {"Curn": "EUR"}},
+                    {"name": "TR.CLOSE", "parameters": {"Scale": "6"}}
+                ]));
+                assert_eq!(parameters["Frq"], "M");
+                let options = r.target.export_options().unwrap().parquet;
+                assert_eq!(options.compression, Compression::Gzip(9));
+                assert_eq!(options.partition, Partition::Year);
+            }
+            r => panic!("Expected a datagrid request, got {:?}", r),
+        }
+
+        let yaml = "requests:\n  - kind: datagrid\n    instruments: [XOM]\n    fields: [TR.CLOSE]\n";
+        assert_eq!(Job::parse(yaml, true).unwrap().requests.len(), 1);
+        assert!(matches!(Job::parse("[[requests]]\nkind = \"quotes\"", false), Err(EkError::Config(_))));
+    }
+
+    #[test]
+    fn test_instruments_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("universe.csv");
+        fs::write(&csv, "Name,RIC\nExxon,XOM\nGameStop,GME\nBlank,\n").unwrap();
+
+        let list = InstrumentList { instruments: vec!["CVX.N".to_string()], instruments_file: Some(csv.to_owned()), instruments_column: Some("RIC".to_string()) };
+        assert_eq!(list.read().unwrap(), vec!["CVX.N", "XOM", "GME"]);
+        let list = InstrumentList { instruments_column: None, ..list };
+        assert_eq!(list.read().unwrap()[1], "Exxon");
+
+        let text = dir.path().join("universe.txt");
+        fs::write(&text, "XOM\n# comment\nGME\n").unwrap();
+        let list = InstrumentList { instruments_file: Some(text), ..InstrumentList::default() };
+        assert_eq!(list.read().unwrap(), vec!["XOM", "GME"]);
+
+        let now = NaiveDate::from_ymd_opt(2023, 3, 10).unwrap().and_hms_opt(18, 30, 0).unwrap();
+        assert_eq!(parse_date("today", now).unwrap(), NaiveDate::from_ymd_opt(2023, 3, 10).unwrap().and_hms_opt(0, 0, 0).unwrap());
+        assert_eq!(parse_date("2020-01-01", now).unwrap(), NaiveDate::from_ymd_opt(2020, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap());
+        assert!(parse_date("yesterday", now).is_err());
+    }
+}
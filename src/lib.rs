@@ -0,0 +1,14 @@
+pub mod auth;
+pub mod cache;
+pub mod calendar;
+pub mod config;
+pub mod connection;
+pub mod datagrid;
+pub mod export;
+pub mod job;
+pub mod platform;
+pub mod rate_limit;
+pub mod report;
+pub mod retry;
+pub mod timeseries;
+pub mod utils;
@@ -1,75 +1,336 @@
-use crate::timeseries::{Interval, TimeSeries};
-use crate::connection::Connection;
-use crate::datagrid::Datagrid;
-use crate::utils::{EkResults, field_builder, Fields};
-use std::collections::HashMap;
+use eikon_downloader::cache::Cache;
+use eikon_downloader::calendar::{Nyse, Weekdays};
+use eikon_downloader::config::{Config, parse_params, read_list};
+use eikon_downloader::connection::{Backend, Connection, Direction};
+use eikon_downloader::datagrid::Datagrid;
+use eikon_downloader::export::{parse_delimiter, write_download, Compression, CsvOptions, ExportOptions, Format, ParquetOptions, Partition, RequestMetadata};
+use eikon_downloader::job::{Job, Runner};
+use eikon_downloader::report::{Failure, Partial, Report};
+use eikon_downloader::timeseries::{Interval, TimeSeries, TsField};
+use eikon_downloader::utils::{EkError, parse_field, parse_timestamp};
 use chrono::prelude::*;
-use crate::utils::Fields::{NoParams, Params};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use polars::prelude::*;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
+/// Downloads Eikon / Refinitiv data to the terminal, CSV, JSON, Parquet or Arrow IPC.
+///
+/// The app key is read from EIKON_APP_KEY or from the configuration file.
+#[derive(Parser)]
+#[command(name = "eikon-dl", version)]
+struct Cli {
+    /// Configuration file, by default $EIKON_DL_CONFIG or eikon-dl/config.toml in the user
+    /// configuration directory
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
 
-mod connection;
-mod datagrid;
-mod timeseries;
-mod utils;
+#[derive(Subcommand)]
+enum Command {
+    /// Download price history
+    Timeseries(TimeseriesArgs),
+    /// Download fundamental and reference data
+    Datagrid(DatagridArgs),
+    /// Check that the proxy or platform can be reached
+    Status,
+    /// Run the requests of a TOML or YAML job file
+    Run {
+        job: PathBuf,
+    },
+}
 
+#[derive(Args)]
+struct Instruments {
+    /// Instruments, comma separated
+    #[arg(short, long, value_delimiter = ',')]
+    instruments: Vec<String>,
+    /// File with one instrument per line
+    #[arg(long)]
+    instruments_file: Option<PathBuf>,
+}
+
+impl Instruments {
+    fn read(&self) -> Result<Vec<String>, EkError> {
+        let mut res = self.instruments.to_owned();
+        if let Some(path) = &self.instruments_file {
+            res.extend(read_list(path)?);
+        }
+        if res.is_empty() {
+            return Err(EkError::Config("No instruments given, use --instruments or --instruments-file".to_string()));
+        }
+        Ok(res)
+    }
+}
 
-fn main() -> () {
-    let api = "f63dab2c283546a187cd6c59894749a2228ce486";
-    let mut ek = Connection::new(api.to_string(), "127.0.0.1".to_string(), 9000);
+#[derive(Args)]
+struct Output {
+    /// File to write, the terminal when not given
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Output format: table, csv, json, parquet, ipc or ipc_stream, guessed from the file
+    /// extension when not given
+    #[arg(long, value_parser = parse_format)]
+    format: Option<Format>,
+    /// Parquet codec with an optional level, e.g. snappy, gzip:9 or zstd:3
+    #[arg(long, default_value = "zstd", value_parser = parse_compression)]
+    compression: Compression,
+    /// Write one Parquet file per ric or per year, the output being a directory. Implies parquet
+    /// when no format is given, other formats are refused
+    #[arg(long, value_parser = parse_partition)]
+    partition_by: Option<Partition>,
+    /// CSV delimiter, a single character or tab
+    #[arg(long, default_value = ",", value_parser = parse_delimiter_arg)]
+    delimiter: u8,
+    /// CSV format of dates
+    #[arg(long, default_value = "%Y-%m-%d")]
+    date_format: String,
+    /// CSV format of timestamps
+    #[arg(long, default_value = "%Y-%m-%dT%H:%M:%S%.f")]
+    datetime_format: String,
+    /// Write what could be downloaded and list the failures instead of stopping at the first one
+    #[arg(long)]
+    partial: bool,
+}
 
-    let ts = TimeSeries::new(ek);
+impl Output {
+    fn write(&self, df: &mut DataFrame, metadata: &RequestMetadata) -> Result<(), EkError> {
+        let options = ExportOptions {
+            csv: CsvOptions {
+                delimiter: self.delimiter,
+                date_format: self.date_format.to_owned(),
+                datetime_format: self.datetime_format.to_owned(),
+                ..CsvOptions::default()
+            },
+            parquet: ParquetOptions {
+                compression: self.compression,
+                partition: self.partition_by.unwrap_or_default(),
+                ..ParquetOptions::default()
+            },
+            ..ExportOptions::default()
+        };
+        write_download(df, self.format, self.output.as_deref(), &options, metadata)
+    }
+}
 
-    let SDate = NaiveDateTime::parse_from_str("1920-01-01T00:00:00", "%FT%T")
-        .unwrap();
-    let EDate = NaiveDateTime::parse_from_str("2023-01-01T00:00:00", "%FT%T")
-        .unwrap();
+#[derive(Args)]
+struct TimeseriesArgs {
+    #[command(flatten)]
+    instruments: Instruments,
+    /// Fields, comma separated
+    #[arg(short, long, value_delimiter = ',', default_value = "*")]
+    fields: Vec<String>,
+    /// Interval, e.g. daily, weekly, minute, 5minutes, tick
+    #[arg(long, default_value = "daily", value_parser = parse_interval)]
+    interval: Interval,
+    /// Start date, e.g. 2020-01-01 or 2020-01-01T09:30:00
+    #[arg(long, value_parser = parse_date)]
+    start: NaiveDateTime,
+    /// End date, now when not given
+    #[arg(long, value_parser = parse_date)]
+    end: Option<NaiveDateTime>,
+    /// Trading calendar the requests are sized with
+    #[arg(long, value_enum, default_value = "weekdays")]
+    calendar: CalendarName,
+    /// Directory of a local cache, only the windows it does not hold are downloaded
+    #[arg(long)]
+    cache: Option<PathBuf>,
+    /// Download the whole window again and replace what the cache holds for it
+    #[arg(long, requires = "cache")]
+    refresh: bool,
+    #[command(flatten)]
+    output: Output,
+}
 
-    match ts.get_timeseries(
-        vec!["US10YT=RR".to_string(), "DE2YT=RR".to_string(), "XOM".to_string()],
-        vec!["*".to_string()],
-        Interval::new("daily"),
-        SDate,
-        EDate,
-    ) {
-        EkResults::DF(df) => println!("{}", df),
-        EkResults::Raw(r) => println!("{:?}", r),
-        EkResults::Err(e) => println!("{}", e.to_string())
-    };
+#[derive(Args)]
+struct DatagridArgs {
+    #[command(flatten)]
+    instruments: Instruments,
+    /// Field with its parameters, e.g. "TR.CLOSE(Curn=EUR)", repeat for several
+    #[arg(short, long)]
+    field: Vec<String>,
+    /// File with one field per line
+    #[arg(long)]
+    fields_file: Option<PathBuf>,
+    /// Request parameter, e.g. SDate=2020-01-01, repeat for several
+    #[arg(short, long)]
+    param: Vec<String>,
+    #[command(flatten)]
+    output: Output,
+}
 
+#[derive(Clone, Copy, ValueEnum)]
+enum CalendarName {
+    Weekdays,
+    Nyse,
+}
+
+fn parse_interval(s: &str) -> Result<Interval, String> {
+    s.parse::<Interval>().map_err(|e| e.to_string())
+}
+
+fn parse_format(s: &str) -> Result<Format, String> {
+    s.parse::<Format>().map_err(|e| e.to_string())
+}
 
-    let mut ek = Connection::new(api.to_string(), "127.0.0.1".to_string(), 9000);
-    let dg = Datagrid::new(ek);
-
-    let mut params: HashMap<String, String> = HashMap::new();
-    params.insert(String::from("EDate"), String::from("2002-02-10"));
-    params.insert(String::from("SDate"), String::from("2002-01-01"));
-    params.insert(String::from("Frq"), String::from("D"));
-
-    let mut settings: HashMap<String, bool> = HashMap::new();
-    settings.insert("raw".to_string(), false);
-    settings.insert("field_name".to_string(), false);
-
-
-    let mut field = HashMap::new();
-    let mut param = HashMap::new();
-    param.insert("Curn".to_string(), "EUR".to_string());
-    field.insert("TR.GrossProfit".to_string(), param);
-    let mut param = HashMap::new();
-    param.insert("Curn".to_string(), "EUR".to_string());
-    field.insert("TR.CLOSE".to_string(), param);
-    let field_json = field_builder(Params(field));
-
-    let fields = field_builder(NoParams(vec![String::from("TR.CLOSE"), String::from("TR.VOLUME"), String::from("TR.CLOSE.DATE")]));
-    let fields = field_builder(NoParams(vec![String::from("TR.CLOSE"), String::from("TR.VOLUME"), String::from("TR.CLOSE.DATE")]));
-    match dg.get_datagrid(
-        vec![String::from("XOM"), String::from("GME")],
-        field_json,
-        Some(params),
-        settings,
-    ) {
-        EkResults::DF(df) => println!("{}", df),
-        EkResults::Raw(r) => println!("{:?}", r),
-        EkResults::Err(e) => println!("{}", e.to_string())
+fn parse_compression(s: &str) -> Result<Compression, String> {
+    s.parse::<Compression>().map_err(|e| e.to_string())
+}
+
+fn parse_partition(s: &str) -> Result<Partition, String> {
+    s.parse::<Partition>().map_err(|e| e.to_string())
+}
+
+fn parse_delimiter_arg(s: &str) -> Result<u8, String> {
+    parse_delimiter(s).map_err(|e| e.to_string())
+}
+
+fn parse_date(s: &str) -> Result<NaiveDateTime, String> {
+    parse_timestamp(s).ok_or(format!("{} is not a date, use 2020-01-01 or 2020-01-01T09:30:00", s))
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    // Tables show every row, polars cuts the middle of long frames by default
+    if std::env::var_os("POLARS_FMT_MAX_ROWS").is_none() {
+        std::env::set_var("POLARS_FMT_MAX_ROWS", "-1");
+    }
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<ExitCode, EkError> {
+    let config = Config::load(cli.config.as_deref())?;
+    match cli.command {
+        Command::Status => status(config.connect()?),
+        Command::Timeseries(args) => timeseries(config.connect()?, args),
+        Command::Datagrid(args) => datagrid(config.connect()?, args),
+        Command::Run { job } => run_job(config, Job::load(&job)?),
+    }
+}
+
+fn run_job(config: Config, job: Job) -> Result<ExitCode, EkError> {
+    let outcomes = Runner::new(config).run(&job);
+    let mut code = ExitCode::SUCCESS;
+    for outcome in outcomes.iter() {
+        let target = match &outcome.output {
+            None => String::new(),
+            Some(p) => format!(" to {}", p.display())
+        };
+        match &outcome.error {
+            Some(e) => eprintln!("{}: failed, {}", outcome.name, e),
+            None => eprintln!("{}: {} rows written{}", outcome.name, outcome.rows, target),
+        }
+        print_report(&outcome.report);
+        if !outcome.is_success() {
+            code = ExitCode::FAILURE;
+        }
+    }
+    Ok(code)
+}
+
+fn status(connection: Connection) -> Result<ExitCode, EkError> {
+    match connection.backend() {
+        Backend::Desktop => println!("Eikon proxy on port {}", connection.get_port()),
+        Backend::Platform(_) => println!("Refinitiv Data Platform"),
+    }
+    let res = connection.check()?;
+    println!("{}", serde_json::to_string_pretty(&res)?);
+    // The server does not report its own count, this is what eikon-dl sent through the usage file
+    for direction in [Direction::TimeSeries, Direction::Datagrid] {
+        println!(
+            "{}: {} requests left today as counted by eikon-dl (client-side estimate)",
+            direction,
+            connection.remaining_daily_requests(direction)
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn timeseries(connection: Connection, args: TimeseriesArgs) -> Result<ExitCode, EkError> {
+    let rics = args.instruments.read()?;
+    let fields = args.fields.iter().map(|f| TsField::from(f.as_str())).collect::<Vec<TsField>>();
+    let end = args.end.unwrap_or(Utc::now().naive_utc());
+
+    let mut ts = TimeSeries::new(connection);
+    match args.calendar {
+        CalendarName::Weekdays => ts.set_calendar(Weekdays),
+        CalendarName::Nyse => ts.set_calendar(Nyse),
+    }
+    ts.set_cache(args.cache.map(|dir| {
+        let mut cache = Cache::new(dir);
+        cache.set_refresh(args.refresh);
+        cache
+    }));
+    let metadata = RequestMetadata::timeseries(&rics, &fields, args.interval, args.start, end);
+    if args.output.partial {
+        let res = ts.get_timeseries_partial(rics, fields, args.interval, args.start, end)?;
+        return write_partial(res, &args.output, &metadata);
+    }
+    let mut df = ts.get_timeseries(rics, fields, args.interval, args.start, end)?;
+    args.output.write(&mut df, &metadata)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn datagrid(connection: Connection, args: DatagridArgs) -> Result<ExitCode, EkError> {
+    let instruments = args.instruments.read()?;
+    let mut fields = args.field.to_owned();
+    if let Some(path) = &args.fields_file {
+        fields.extend(read_list(path)?);
+    }
+    if fields.is_empty() {
+        return Err(EkError::Config("No fields given, use --field or --fields-file".to_string()));
+    }
+    let fields = Value::Array(fields.iter().map(|f| parse_field(f)).collect::<Result<Vec<Value>, EkError>>()?);
+    let parameters = match args.param.is_empty() {
+        true => None,
+        false => Some(parse_params(&args.param)?)
     };
+
+    let dg = Datagrid::new(connection);
+    let metadata = RequestMetadata::datagrid(&instruments, &fields, parameters.as_ref());
+    if args.output.partial {
+        let res = dg.get_datagrid_partial(instruments, fields, parameters)?;
+        return write_partial(res, &args.output, &metadata);
+    }
+    let mut df = dg.get_datagrid(instruments, fields, parameters)?;
+    args.output.write(&mut df, &metadata)?;
+    Ok(ExitCode::SUCCESS)
 }
 
+/// Writes what was downloaded and lists the failures on stderr, failing when anything is missing.
+fn write_partial(res: Partial<DataFrame>, output: &Output, metadata: &RequestMetadata) -> Result<ExitCode, EkError> {
+    if let Some(mut df) = res.data {
+        output.write(&mut df, metadata)?;
+    }
+    print_report(&res.report);
+    match res.report.chunks.is_empty() && res.report.rics.is_empty() {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::FAILURE)
+    }
+}
+
+fn print_report(report: &Report) {
+    let describe = |f: &Failure| {
+        let window = match (&f.start_date, &f.end_date) {
+            (Some(s), Some(e)) => format!(" from {} to {}", s, e),
+            _ => String::new()
+        };
+        format!("{}{}: {}", f.instruments.join(","), window, f.message)
+    };
+    for f in report.chunks.iter().chain(report.rics.iter()) {
+        eprintln!("Failed {}", describe(f));
+    }
+    for m in report.missing_fields.iter() {
+        eprintln!("{} did not return {}", m.ric, m.fields.join(", "));
+    }
+}
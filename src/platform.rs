@@ -0,0 +1,459 @@
+use serde_json::{json, Value};
+use log::{debug, warn};
+use crate::auth::Token;
+use crate::utils::{clean_string, field_string, EkError};
+
+/// Default address of the Refinitiv Data Platform REST API.
+const DEFAULT_BASE_URL: &str = "https://api.refinitiv.com";
+
+/// Most rows the historical-pricing endpoints return for one request.
+const MAX_ROWS: usize = 10000;
+
+/// Desktop TimeSeries field names and the historical-pricing fields they correspond to.
+///
+/// `TIMESTAMP` is not listed as it is always returned, as `DATE` for interday summaries and
+/// `DATE_TIME` for intraday summaries.
+const FIELD_MAP: [(&str, &str); 6] = [
+    ("OPEN", "OPEN_PRC"),
+    ("HIGH", "HIGH_1"),
+    ("LOW", "LOW_1"),
+    ("CLOSE", "TRDPRC_1"),
+    ("VOLUME", "ACVOL_UNS"),
+    ("COUNT", "NUM_MOVES"),
+];
+
+/// Credentials and address used to reach the Refinitiv Data Platform.
+#[derive(Clone, Debug)]
+pub struct PlatformConfig {
+    pub base_url: String,
+    pub username: String,
+    pub password: String,
+    /// Application key, used as the OAuth2 client id
+    pub client_id: String,
+    pub scope: String,
+}
+
+impl PlatformConfig {
+    pub fn new(client_id: String, username: String, password: String) -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            username,
+            password,
+            client_id,
+            scope: "trapi".to_string(),
+        }
+    }
+
+    fn token_url(&self) -> String {
+        format!("{}/auth/oauth2/v1/token", self.base_url)
+    }
+}
+
+fn password_grant(client: &reqwest::Client, config: &PlatformConfig) -> reqwest::RequestBuilder {
+    client.post(config.token_url())
+        .form(&[
+            ("grant_type", "password"),
+            ("username", config.username.as_str()),
+            ("password", config.password.as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("scope", config.scope.as_str()),
+            ("takeExclusiveSignOnControl", "true"),
+        ])
+}
+
+fn refresh_grant(client: &reqwest::Client, config: &PlatformConfig, refresh_token: &str) -> reqwest::RequestBuilder {
+    client.post(config.token_url())
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("username", config.username.as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("refresh_token", refresh_token),
+        ])
+}
+
+async fn request_token(req: reqwest::RequestBuilder) -> Result<Value, EkError> {
+    let r = match req.send().await {
+        Ok(r) => r,
+        Err(e) => return Err(e.into())
+    };
+    let status = r.status();
+    let text = r.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(EkError::AuthError(format!("{}: {}", status, text)));
+    }
+    match serde_json::from_str::<Value>(&text) {
+        Ok(r) => Ok(r),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Signs in with the username and password.
+pub async fn sign_in(client: &reqwest::Client, config: &PlatformConfig) -> Result<Value, EkError> {
+    request_token(password_grant(client, config)).await
+}
+
+/// Gets a new access token, using the refresh token of `previous` when there is one and falling
+/// back to signing in again if the refresh is refused.
+pub async fn fetch_token(
+    client: &reqwest::Client,
+    config: &PlatformConfig,
+    previous: Option<Token>,
+) -> Result<Token, EkError> {
+    if let Some(refresh_token) = previous.and_then(|t| t.refresh_token) {
+        match request_token(refresh_grant(client, config, &refresh_token)).await {
+            Ok(r) => return Token::from_response(&r),
+            Err(e) => warn!("Refreshing the access token failed, signing in again: {}", e)
+        }
+    }
+    Token::from_response(&sign_in(client, config).await?)
+}
+
+fn rdp_field(field: &str) -> &str {
+    match FIELD_MAP.iter().find(|(desktop, _)| *desktop == field) {
+        None => field,
+        Some((_, rdp)) => rdp
+    }
+}
+
+fn desktop_field(field: &str) -> &str {
+    if field == "DATE" || field == "DATE_TIME" {
+        return "TIMESTAMP";
+    }
+    match FIELD_MAP.iter().find(|(_, rdp)| *rdp == field) {
+        None => field,
+        Some((desktop, _)) => desktop
+    }
+}
+
+/// Historical-pricing view and interval code for a desktop TimeSeries interval.
+fn view_interval(interval: &str) -> (&'static str, &'static str) {
+    match interval {
+        "tick" | "taq" => ("events", ""),
+        "minute" => ("intraday-summaries", "PT1M"),
+        "5minutes" => ("intraday-summaries", "PT5M"),
+        "10minutes" => ("intraday-summaries", "PT10M"),
+        "30minutes" => ("intraday-summaries", "PT30M"),
+        "60minutes" => ("intraday-summaries", "PT60M"),
+        "hour" => ("intraday-summaries", "PT1H"),
+        "weekly" => ("interday-summaries", "P1W"),
+        "monthly" => ("interday-summaries", "P1M"),
+        "quarterly" => ("interday-summaries", "P3M"),
+        "yearly" => ("interday-summaries", "P1Y"),
+        _ => ("interday-summaries", "P1D")
+    }
+}
+
+fn utc_date(date: &Value) -> String {
+    let date = clean_string(date.to_string());
+    if date.ends_with('Z') { date } else { format!("{}Z", date) }
+}
+
+/// Builds the historical-pricing request for one RIC of a desktop TimeSeries payload.
+pub fn timeseries_request(
+    client: &reqwest::Client,
+    config: &PlatformConfig,
+    payload: &Value,
+    ric: &str,
+    bearer: &str,
+) -> reqwest::RequestBuilder {
+    let (view, interval) = view_interval(payload["interval"].as_str().unwrap_or("daily"));
+    let mut query = vec![
+        ("start", utc_date(&payload["startdate"])),
+        ("end", utc_date(&payload["enddate"])),
+        ("count", MAX_ROWS.to_string()),
+    ];
+    // Events are not summarised
+    if !interval.is_empty() {
+        query.push(("interval", interval.to_string()));
+    }
+    let fields = payload["fields"].as_array()
+        .map(|f| f.iter().filter_map(|v| v.as_str()).collect::<Vec<&str>>())
+        .unwrap_or_default();
+    if !fields.is_empty() && !fields.contains(&"*") {
+        let fields = fields.iter()
+            .filter(|f| **f != "TIMESTAMP")
+            .map(|f| rdp_field(f))
+            .collect::<Vec<&str>>();
+        query.push(("fields", fields.join(",")));
+    }
+    let address = format!("{}/data/historical-pricing/v1/views/{}/{}", config.base_url, view, ric);
+    debug!("{} {:?}", address, query);
+    client.get(address)
+        .query(&query)
+        .header("Authorization", bearer)
+}
+
+/// Converts a historical-pricing response into an entry of a desktop `timeseriesData` array.
+///
+/// Columns are renamed to the desktop field names with `TIMESTAMP` first, and rows are put in
+/// ascending order as the platform returns the most recent row first.
+pub fn timeseries_data(ric: &str, res: &Value) -> Value {
+    let item = if res.is_array() { &res[0] } else { res };
+    let headers = match item["headers"].as_array() {
+        Some(r) => r,
+        None => {
+            let status = if item["status"].is_null() { &item["error"] } else { &item["status"] };
+            return timeseries_error(ric, &clean_string(status["code"].to_string()), &clean_string(status["message"].to_string()));
+        }
+    };
+
+    let names = headers.iter()
+        .map(|h| desktop_field(h["name"].as_str().unwrap_or_default()).to_string())
+        .collect::<Vec<String>>();
+    let mut order: Vec<usize> = (0..names.len()).collect();
+    order.sort_by_key(|i| names[*i] != "TIMESTAMP");
+
+    let fields = order.iter()
+        .map(|i| {
+            let kind = match (names[*i].as_str(), headers[*i]["type"].as_str()) {
+                ("TIMESTAMP", _) => "DateTime",
+                (_, Some("number")) => "Double",
+                (_, Some("integer")) => "Long",
+                _ => "String"
+            };
+            json!({"name": names[*i], "type": kind})
+        })
+        .collect::<Vec<Value>>();
+
+    let mut rows = Vec::new();
+    for row in item["data"].as_array().unwrap_or(&Vec::new()).iter().rev() {
+        let row = order.iter()
+            .map(|i| {
+                if names[*i] == "TIMESTAMP" {
+                    match row[*i].as_str() {
+                        Some(d) if d.len() == 10 => json!(format!("{}T00:00:00Z", d)),
+                        _ => row[*i].to_owned()
+                    }
+                } else {
+                    row[*i].to_owned()
+                }
+            })
+            .collect::<Vec<Value>>();
+        rows.push(Value::Array(row));
+    }
+
+    json!({"ric": ric, "statusCode": "Normal", "fields": fields, "dataPoints": rows})
+}
+
+/// Desktop `timeseriesData` entry for a RIC the platform could not return data for.
+pub fn timeseries_error(ric: &str, code: &str, message: &str) -> Value {
+    json!({"ric": ric, "statusCode": "Error", "errorCode": code, "errorMessage": message})
+}
+
+/// Builds the data-grid request for a desktop Datagrid payload.
+pub fn datagrid_request(
+    client: &reqwest::Client,
+    config: &PlatformConfig,
+    payload: &Value,
+    bearer: &str,
+) -> reqwest::RequestBuilder {
+    let request = &payload["requests"][0];
+    let fields = request["fields"].as_array()
+        .map(|f| f.iter().map(field_string).collect::<Vec<String>>())
+        .unwrap_or_default();
+    let mut body = json!({"universe": request["instruments"], "fields": fields});
+    if request["parameters"].is_object() {
+        body["parameters"] = request["parameters"].to_owned();
+    }
+    client.post(format!("{}/data/datagrid/beta1/", config.base_url))
+        .header("Authorization", bearer)
+        .json(&body)
+}
+
+/// Converts a data-grid response into the desktop Datagrid response layout.
+pub fn datagrid_response(res: &Value) -> Value {
+    let headers = res["headers"].as_array()
+        .map(|h| h.iter()
+            .map(|v| {
+                if v["name"] == "instrument" {
+                    json!({"displayName": v["title"]})
+                } else {
+                    json!({"displayName": v["title"], "field": v["name"]})
+                }
+            })
+            .collect::<Vec<Value>>())
+        .unwrap_or_default();
+    let error = if res["error"].is_array() { res["error"].to_owned() } else { json!([]) };
+    json!({"responses": [{"headers": [headers], "data": res["data"], "error": error}]})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use crate::connection::Connection;
+    use crate::datagrid::Datagrid;
+    use crate::timeseries::{Interval, TimeSeries, TsField};
+    use crate::utils::{field_builder, Fields};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Minimal HTTP server answering each request with `route(method, path, body)`.
+    ///
+    /// # Returns
+    ///
+    /// The base url of the server
+    fn mock_server<F>(route: F) -> String
+        where F: Fn(&str, &str, &str) -> (u16, Value) + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(r) => r,
+                    Err(_) => continue
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let parts = line.split_whitespace().map(|s| s.to_string()).collect::<Vec<String>>();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    let lower = header.to_lowercase();
+                    if let Some(v) = lower.strip_prefix("content-length:") {
+                        length = v.trim().parse::<usize>().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                let (status, res) = route(&parts[0], &parts[1], &String::from_utf8_lossy(&body));
+                let res = res.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, res.len(), res
+                ).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_timeseries_data() {
+        let res = json!([{
+            "universe": {"ric": "XOM"},
+            "headers": [{"name": "DATE", "type": "string"}, {"name": "TRDPRC_1", "type": "number"}, {"name": "ACVOL_UNS", "type": "integer"}],
+            "data": [["2023-01-04", 110.5, 1000], ["2023-01-03", 108.2, 2000]]
+        }]);
+        let data = timeseries_data("XOM", &res);
+        assert_eq!(data["statusCode"], "Normal");
+        assert_eq!(data["fields"], json!([
+            {"name": "TIMESTAMP", "type": "DateTime"},
+            {"name": "CLOSE", "type": "Double"},
+            {"name": "VOLUME", "type": "Long"}
+        ]));
+        assert_eq!(data["dataPoints"], json!([["2023-01-03T00:00:00Z", 108.2, 2000], ["2023-01-04T00:00:00Z", 110.5, 1000]]));
+
+        let res = json!([{"universe": {"ric": "NOPE"}, "status": {"code": "TS.Interday.UserNotPermission.92000", "message": "No permission"}}]);
+        let data = timeseries_data("NOPE", &res);
+        assert_eq!(data["statusCode"], "Error");
+        assert_eq!(data["errorMessage"], "No permission");
+    }
+
+    #[test]
+    fn test_datagrid_response() {
+        let res = json!({
+            "headers": [{"name": "instrument", "title": "Instrument"}, {"name": "TR.CLOSE", "title": "Close Price"}],
+            "data": [["XOM", 110.5]]
+        });
+        assert_eq!(datagrid_response(&res), json!({"responses": [{
+            "headers": [[{"displayName": "Instrument"}, {"displayName": "Close Price", "field": "TR.CLOSE"}]],
+            "data": [["XOM", 110.5]],
+            "error": []
+        }]}));
+    }
+
+    #[test]
+    fn test_fetch_token() {
+        let base_url = mock_server(|_, path, body| {
+            assert_eq!(path, "/auth/oauth2/v1/token");
+            if body.contains("grant_type=refresh_token") {
+                (400, json!({"error": "invalid_grant"}))
+            } else {
+                assert!(body.contains("grant_type=password"));
+                (200, json!({"access_token": "abc", "refresh_token": "def", "expires_in": "300"}))
+            }
+        });
+        let mut config = PlatformConfig::new("key".to_string(), "user".to_string(), "pass".to_string());
+        config.base_url = base_url;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let client = reqwest::Client::new();
+        let token = rt.block_on(fetch_token(&client, &config, None)).unwrap();
+        assert_eq!(token.access_token, "abc");
+        assert_eq!(token.refresh_token, Some("def".to_string()));
+
+        // A refused refresh falls back to the password grant
+        let token = rt.block_on(fetch_token(&client, &config, Some(token))).unwrap();
+        assert_eq!(token.access_token, "abc");
+    }
+
+    #[test]
+    fn test_platform_connection() {
+        let base_url = mock_server(|method, path, body| {
+            match (method, path.split('?').next().unwrap_or_default()) {
+                ("POST", "/auth/oauth2/v1/token") => {
+                    (200, json!({"access_token": "abc", "refresh_token": "def", "expires_in": "600"}))
+                }
+                ("GET", "/data/historical-pricing/v1/views/interday-summaries/XOM") => {
+                    assert!(path.contains("fields=TRDPRC_1"));
+                    (200, json!([{
+                        "universe": {"ric": "XOM"},
+                        "headers": [{"name": "DATE", "type": "string"}, {"name": "TRDPRC_1", "type": "number"}],
+                        "data": [["2023-01-04", 110.5], ["2023-01-03", 108.2]]
+                    }]))
+                }
+                ("GET", _) => (404, json!({"error": {"code": "404", "message": "Unknown RIC"}})),
+                ("POST", "/data/datagrid/beta1/") => {
+                    assert!(body.contains("TR.CLOSE"));
+                    (200, json!({
+                        "headers": [{"name": "instrument", "title": "Instrument"}, {"name": "TR.CLOSE", "title": "Close Price"}],
+                        "data": [["XOM", 110.5]]
+                    }))
+                }
+                _ => (500, json!({}))
+            }
+        });
+        let mut config = PlatformConfig::new("key".to_string(), "user".to_string(), "pass".to_string());
+        config.base_url = base_url;
+
+        let ts = TimeSeries::new(Connection::platform(config.to_owned()));
+        let res = ts.get_timeseries(
+            vec!["XOM".to_string(), "NOPE".to_string()],
+            vec![TsField::Close],
+            Interval::Daily,
+            NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            NaiveDate::from_ymd_opt(2023, 1, 5).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+        );
+        let df = res.unwrap();
+        assert_eq!(df.shape().0, 2);
+        assert_eq!(df.get_column_names(), vec!["TIMESTAMP", "CLOSE", "RIC"]);
+
+        let res = ts.get_timeseries_partial(
+            vec!["XOM".to_string(), "NOPE".to_string()],
+            vec![TsField::Close],
+            Interval::Daily,
+            NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            NaiveDate::from_ymd_opt(2023, 1, 5).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+        ).unwrap();
+        assert_eq!(res.data.unwrap().shape().0, 2);
+        assert!(res.report.chunks.is_empty());
+        assert_eq!(res.report.failed_instruments(), vec!["NOPE"]);
+        assert_eq!(res.report.rics[0].code, Some("404".to_string()));
+
+        let dg = Datagrid::new(Connection::platform(config));
+        let res = dg.get_datagrid(
+            vec!["XOM".to_string()],
+            field_builder(Fields::NoParams(vec!["TR.CLOSE".to_string()])),
+            None,
+        );
+        assert_eq!(res.unwrap().shape(), (1, 2));
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time;
+use chrono::prelude::*;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use crate::connection::Direction;
+use crate::utils::EkError;
+
+const MINUTE: time::Duration = time::Duration::from_secs(60);
+const SECOND: time::Duration = time::Duration::from_secs(1);
+/// Longest time the requests sent are not written to the usage file
+const SAVE_EVERY: time::Duration = time::Duration::from_secs(5);
+
+/// Request budget for one `Direction`, defaults are the documented Eikon Data API limits.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub requests_per_second: u32,
+    pub requests_per_day: u32,
+    pub bytes_per_minute: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 5,
+            requests_per_day: 10_000,
+            bytes_per_minute: 50 * 1024 * 1024,
+        }
+    }
+}
+
+/// Bucket holding up to `capacity` tokens, refilled evenly over `period`.
+///
+/// Tokens can go negative when usage is only known after the fact (data volume), later
+/// requests then wait until the debt is paid back.
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last: time::Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, period: time::Duration, now: time::Instant) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / period.as_secs_f64(),
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: time::Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Time until `amount` tokens are available.
+    fn wait_for(&self, amount: f64) -> time::Duration {
+        if self.tokens >= amount || self.refill_per_sec <= 0f64 {
+            return time::Duration::ZERO;
+        }
+        time::Duration::from_secs_f64((amount - self.tokens) / self.refill_per_sec)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+/// Requests sent on one UTC day.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct DailyUsage {
+    day: NaiveDate,
+    used: u32,
+}
+
+impl DailyUsage {
+    /// Requests sent on `today`, starting again from zero on a new day.
+    fn used_on(&mut self, today: NaiveDate) -> u32 {
+        if self.day != today {
+            *self = DailyUsage { day: today, used: 0 };
+        }
+        self.used
+    }
+}
+
+struct Buckets {
+    per_second: TokenBucket,
+    volume: TokenBucket,
+    per_day: u32,
+    usage: DailyUsage,
+    /// Requests counted in `usage` that are not in the usage file yet
+    unsaved: DailyUsage,
+}
+
+impl Buckets {
+    fn new(limits: &Limits, now: time::Instant, today: NaiveDate) -> Self {
+        Self {
+            per_second: TokenBucket::new(limits.requests_per_second as f64, SECOND, now),
+            volume: TokenBucket::new(limits.bytes_per_minute as f64, MINUTE, now),
+            per_day: limits.requests_per_day,
+            usage: DailyUsage { day: today, used: 0 },
+            unsaved: DailyUsage { day: today, used: 0 },
+        }
+    }
+
+    fn refill(&mut self, now: time::Instant) {
+        self.per_second.refill(now);
+        self.volume.refill(now);
+    }
+
+    fn remaining_on(&mut self, today: NaiveDate) -> u32 {
+        self.per_day.saturating_sub(self.usage.used_on(today))
+    }
+
+    fn count(&mut self, today: NaiveDate) {
+        self.usage.used_on(today);
+        self.usage.used += 1;
+        self.unsaved.used_on(today);
+        self.unsaved.used += 1;
+    }
+}
+
+/// File the daily counts are shared through.
+struct UsageFile {
+    path: PathBuf,
+    saved: time::Instant,
+}
+
+/// Token-bucket rate limiter keeping a separate budget for every `Direction`.
+///
+/// The daily quota counts the requests sent since midnight UTC. It only covers this limiter,
+/// unless a usage file is set with `set_usage_file`: the count is then shared with every limiter
+/// using the same file, so that a job started later knows what earlier runs used.
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<Direction, Buckets>>,
+    usage_file: Mutex<Option<UsageFile>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            usage_file: Mutex::new(None),
+        }
+    }
+
+    /// Replaces the limits for `direction`, keeping the requests already counted today.
+    pub fn set_limits(&self, direction: Direction, limits: &Limits) {
+        let today = Utc::now().date_naive();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut b = Buckets::new(limits, time::Instant::now(), today);
+        if let Some(old) = buckets.get(&direction) {
+            b.usage = old.usage;
+        }
+        buckets.insert(direction, b);
+    }
+
+    /// Counts the daily quota in `path`, reading the requests earlier runs sent today.
+    ///
+    /// Every limiter adds the requests it sent to the count held in the file, at most
+    /// `SAVE_EVERY` after sending them and when it is dropped, so limiters running at the same
+    /// time do not overwrite each other's counts.
+    pub fn set_usage_file(&self, path: &Path) -> Result<(), EkError> {
+        let mut file = self.usage_file.lock().unwrap();
+        let mut usage_file = UsageFile { path: path.to_path_buf(), saved: time::Instant::now() };
+        self.save(&mut usage_file)?;
+        *file = Some(usage_file);
+        Ok(())
+    }
+
+    /// Takes one request from the budget of `direction`.
+    ///
+    /// # Returns
+    ///
+    /// The time to wait before the request may be sent, or `EkError::RateLimited` once the daily
+    /// quota is used up
+    fn try_acquire(&self, direction: Direction, now: time::Instant, today: NaiveDate) -> Result<time::Duration, EkError> {
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let b = buckets.entry(direction)
+                .or_insert_with(|| Buckets::new(&Limits::default(), now, today));
+            b.refill(now);
+
+            if b.remaining_on(today) == 0 {
+                return Err(EkError::RateLimited(format!("Daily request quota for {} is used up", direction)));
+            }
+            let wait = b.per_second.wait_for(1f64).max(b.volume.wait_for(0f64));
+            if wait.is_zero() {
+                b.per_second.take(1f64);
+                b.count(today);
+            }
+            wait
+        };
+        // A limiter already writing the file saves this request too
+        if let Ok(mut file) = self.usage_file.try_lock() {
+            if let Some(f) = file.as_mut().filter(|f| now.saturating_duration_since(f.saved) >= SAVE_EVERY) {
+                if let Err(e) = self.save(f) {
+                    warn!("Could not write the request count to {}: {}", f.path.display(), e);
+                }
+            }
+        }
+        Ok(wait)
+    }
+
+    /// Writes the requests not saved yet to the usage file, if one is set.
+    pub fn flush(&self) {
+        if let Some(f) = self.usage_file.lock().unwrap().as_mut() {
+            if let Err(e) = self.save(f) {
+                warn!("Could not write the request count to {}: {}", f.path.display(), e);
+            }
+        }
+    }
+
+    /// Adds the unsaved requests to the usage file, then takes the counts of the other limiters
+    /// from it. The buckets are not locked while the file is written.
+    fn save(&self, file: &mut UsageFile) -> Result<(), EkError> {
+        let today = Utc::now().date_naive();
+        // Requests of an earlier day no longer count
+        let unsaved = self.buckets.lock().unwrap().iter_mut()
+            .filter_map(|(d, b)| {
+                let used = b.unsaved.used_on(today);
+                b.unsaved.used = 0;
+                (used > 0).then_some((*d, used))
+            })
+            .collect::<Vec<(Direction, u32)>>();
+        file.saved = time::Instant::now();
+        let usage = match add_usage(&file.path, &unsaved, today) {
+            Ok(u) => u,
+            Err(e) => {
+                // Kept for the next attempt
+                let mut buckets = self.buckets.lock().unwrap();
+                for (d, used) in unsaved {
+                    if let Some(b) = buckets.get_mut(&d) {
+                        b.unsaved.used += used;
+                    }
+                }
+                return Err(e);
+            }
+        };
+        let now = time::Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        for direction in [Direction::Datagrid, Direction::TimeSeries] {
+            if let Some(u) = usage.get(&direction.to_string()).filter(|u| u.day == today) {
+                let b = buckets.entry(direction)
+                    .or_insert_with(|| Buckets::new(&Limits::default(), now, today));
+                b.usage.used = b.usage.used_on(today).max(u.used);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until a request for `direction` fits in the budget and takes it.
+    pub async fn acquire(&self, direction: Direction) -> Result<(), EkError> {
+        loop {
+            let wait = self.try_acquire(direction, time::Instant::now(), Utc::now().date_naive())?;
+            if wait.is_zero() {
+                return Ok(());
+            }
+            debug!("Rate limit reached for {}, waiting {:?}", direction, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Records the size of a response against the per-minute data volume of `direction`.
+    pub fn record_volume(&self, direction: Direction, bytes: usize) {
+        let now = time::Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let b = buckets.entry(direction)
+            .or_insert_with(|| Buckets::new(&Limits::default(), now, Utc::now().date_naive()));
+        b.refill(now);
+        b.volume.take(bytes as f64);
+    }
+
+    /// Number of requests left in today's quota of `direction`, as counted by this limiter.
+    pub fn remaining_daily(&self, direction: Direction) -> u32 {
+        let mut buckets = self.buckets.lock().unwrap();
+        match buckets.get_mut(&direction) {
+            None => Limits::default().requests_per_day,
+            Some(b) => b.remaining_on(Utc::now().date_naive())
+        }
+    }
+}
+
+impl Drop for RateLimiter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Adds requests sent `today` to the counts of the usage file, locking it meanwhile.
+///
+/// # Returns
+///
+/// The counts of every direction after the addition
+fn add_usage(path: &Path, sent: &[(Direction, u32)], today: NaiveDate) -> Result<HashMap<String, DailyUsage>, EkError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    file.lock()?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    let mut usage: HashMap<String, DailyUsage> = match text.trim().is_empty() {
+        true => HashMap::new(),
+        false => serde_json::from_str(&text)?
+    };
+    for (direction, used) in sent {
+        let u = usage.entry(direction.to_string()).or_insert(DailyUsage { day: today, used: 0 });
+        u.used_on(today);
+        u.used += used;
+    }
+    if !sent.is_empty() {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&serde_json::to_vec(&usage)?)?;
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: Limits) -> RateLimiter {
+        let limiter = RateLimiter::new();
+        limiter.set_limits(Direction::TimeSeries, &limits);
+        limiter
+    }
+
+    fn wait(limiter: &RateLimiter, direction: Direction, now: time::Instant) -> time::Duration {
+        limiter.try_acquire(direction, now, Utc::now().date_naive()).unwrap()
+    }
+
+    #[test]
+    fn test_per_second() {
+        let limiter = limiter(Limits { requests_per_second: 2, ..Limits::default() });
+        let now = time::Instant::now();
+        assert!(wait(&limiter, Direction::TimeSeries, now).is_zero());
+        assert!(wait(&limiter, Direction::TimeSeries, now).is_zero());
+        let w = wait(&limiter, Direction::TimeSeries, now);
+        assert!(w > time::Duration::from_millis(400) && w <= time::Duration::from_millis(500));
+
+        // Other directions keep their own budget
+        assert!(wait(&limiter, Direction::Datagrid, now).is_zero());
+        assert!(wait(&limiter, Direction::TimeSeries, now + SECOND).is_zero());
+    }
+
+    #[test]
+    fn test_daily_quota() {
+        let limiter = limiter(Limits { requests_per_day: 3, ..Limits::default() });
+        let now = time::Instant::now();
+        for _ in 0..3 {
+            wait(&limiter, Direction::TimeSeries, now);
+        }
+        assert_eq!(limiter.remaining_daily(Direction::TimeSeries), 0);
+        let today = Utc::now().date_naive();
+        match limiter.try_acquire(Direction::TimeSeries, now, today) {
+            Err(EkError::RateLimited(_)) => {}
+            _ => panic!("Expected the daily quota to be used up"),
+        }
+        assert_eq!(limiter.remaining_daily(Direction::Datagrid), 10_000);
+        // The quota starts again at midnight UTC, not one day after each request
+        let tomorrow = today.succ_opt().unwrap();
+        assert!(limiter.try_acquire(Direction::TimeSeries, now + SECOND, tomorrow).unwrap().is_zero());
+    }
+
+    #[test]
+    fn test_usage_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usage.json");
+        let first = limiter(Limits { requests_per_day: 5, ..Limits::default() });
+        first.set_usage_file(&path).unwrap();
+        let start = time::Instant::now();
+        for _ in 0..2 {
+            wait(&first, Direction::TimeSeries, start);
+        }
+
+        // The file is only written every few seconds
+        let second = limiter(Limits { requests_per_day: 5, ..Limits::default() });
+        second.set_usage_file(&path).unwrap();
+        assert_eq!(second.remaining_daily(Direction::TimeSeries), 5);
+        wait(&first, Direction::TimeSeries, start + SAVE_EVERY);
+        second.set_usage_file(&path).unwrap();
+        assert_eq!(second.remaining_daily(Direction::TimeSeries), 2);
+        assert_eq!(second.remaining_daily(Direction::Datagrid), 10_000);
+
+        // Limiters sharing the file add their requests rather than overwrite each other's
+        wait(&second, Direction::TimeSeries, start);
+        wait(&first, Direction::Datagrid, start);
+        drop(first);
+        second.flush();
+        assert_eq!(second.remaining_daily(Direction::TimeSeries), 1);
+        assert_eq!(second.remaining_daily(Direction::Datagrid), 9_999);
+
+        let yesterday = Utc::now().date_naive().pred_opt().unwrap();
+        let stale = HashMap::from([(Direction::TimeSeries.to_string(), DailyUsage { day: yesterday, used: 5 })]);
+        fs::write(&path, serde_json::to_vec(&stale).unwrap()).unwrap();
+        let third = limiter(Limits { requests_per_day: 5, ..Limits::default() });
+        third.set_usage_file(&path).unwrap();
+        assert_eq!(third.remaining_daily(Direction::TimeSeries), 5);
+    }
+
+    #[test]
+    fn test_volume() {
+        let limiter = limiter(Limits { bytes_per_minute: 600, ..Limits::default() });
+        limiter.record_volume(Direction::TimeSeries, 660);
+        let w = wait(&limiter, Direction::TimeSeries, time::Instant::now());
+        assert!(w > time::Duration::from_secs(5) && w <= time::Duration::from_secs(6));
+    }
+}
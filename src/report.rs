@@ -0,0 +1,150 @@
+use serde_json::Value;
+use crate::utils::{clean_string, EkError};
+
+/// Something that could not be downloaded, with enough detail to request it again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    /// Server error code, when the server sent one
+    pub code: Option<String>,
+    pub message: String,
+    pub instruments: Vec<String>,
+    /// Start of the requested date window, when the request had one
+    pub start_date: Option<String>,
+    /// End of the requested date window, when the request had one
+    pub end_date: Option<String>,
+}
+
+impl Failure {
+    /// Failure of a whole chunk, `payload` being the TimeSeries or Datagrid payload that was sent.
+    pub fn from_chunk(payload: &Value, e: &EkError) -> Self {
+        let code = match e {
+            EkError::ServerError(code, _) => Some(code.to_string()),
+            _ => None
+        };
+        let (instruments, start_date, end_date) = describe(payload);
+        Self {
+            code,
+            message: e.to_string(),
+            instruments,
+            start_date,
+            end_date,
+        }
+    }
+
+    /// Failure of one RIC inside a TimeSeries response that was otherwise received.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The chunk payload the RIC was requested in
+    /// * `entry` - The `timeseriesData` entry of the RIC
+    pub fn from_ric(payload: &Value, entry: &Value) -> Self {
+        let (_, start_date, end_date) = describe(payload);
+        let code = match &entry["errorCode"] {
+            Value::Null => None,
+            c => Some(clean_string(c.to_string()))
+        };
+        let message = match &entry["errorMessage"] {
+            Value::Null => format!("Status {}", clean_string(entry["statusCode"].to_string())),
+            m => clean_string(m.to_string())
+        };
+        Self {
+            code,
+            message,
+            instruments: vec![clean_string(entry["ric"].to_string())],
+            start_date,
+            end_date,
+        }
+    }
+}
+
+/// Instruments and date window of a TimeSeries or Datagrid payload.
+fn describe(payload: &Value) -> (Vec<String>, Option<String>, Option<String>) {
+    let request = &payload["requests"][0];
+    let instruments = if payload["rics"].is_array() { &payload["rics"] } else { &request["instruments"] };
+    let instruments = instruments.as_array()
+        .map(|v| v.iter().map(|i| clean_string(i.to_string())).collect::<Vec<String>>())
+        .unwrap_or_default();
+    let date = |v: &Value| match v {
+        Value::Null => None,
+        d => Some(clean_string(d.to_string()))
+    };
+    if payload["rics"].is_array() {
+        (instruments, date(&payload["startdate"]), date(&payload["enddate"]))
+    } else {
+        (instruments, date(&request["parameters"]["SDate"]), date(&request["parameters"]["EDate"]))
+    }
+}
+
+/// Requested fields a RIC returned data without.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MissingFields {
+    pub ric: String,
+    pub fields: Vec<String>,
+}
+
+/// What failed during a download, split into chunks that failed as a whole and single RICs that
+/// failed within a chunk that succeeded.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub chunks: Vec<Failure>,
+    pub rics: Vec<Failure>,
+    /// RICs that returned data, but not for every requested field
+    pub missing_fields: Vec<MissingFields>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.rics.is_empty() && self.missing_fields.is_empty()
+    }
+
+    /// Every instrument that is missing at least part of its data, without duplicates.
+    pub fn failed_instruments(&self) -> Vec<String> {
+        let mut res: Vec<String> = Vec::new();
+        for f in self.chunks.iter().chain(self.rics.iter()) {
+            for i in f.instruments.iter() {
+                if !res.contains(i) {
+                    res.push(i.to_owned());
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Data that could be downloaded together with a report of what could not.
+#[derive(Debug)]
+pub struct Partial<T> {
+    /// `None` when nothing could be downloaded
+    pub data: Option<T>,
+    pub report: Report,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_failures() {
+        let payload = json!({"rics": ["XOM", "NOPE"], "fields": ["*"], "interval": "daily", "startdate": "2023-01-01T00:00:00", "enddate": "2023-02-01T00:00:00"});
+        let f = Failure::from_chunk(&payload, &EkError::ServerError(2504, "Backend error".to_string()));
+        assert_eq!(f.code, Some("2504".to_string()));
+        assert_eq!(f.instruments, vec!["XOM", "NOPE"]);
+        assert_eq!(f.start_date, Some("2023-01-01T00:00:00".to_string()));
+
+        let entry = json!({"ric": "NOPE", "statusCode": "Error", "errorCode": "TS.Interday.UserRequestError.70007", "errorMessage": "Invalid RIC"});
+        let f = Failure::from_ric(&payload, &entry);
+        assert_eq!(f.code, Some("TS.Interday.UserRequestError.70007".to_string()));
+        assert_eq!(f.message, "Invalid RIC");
+        assert_eq!(f.end_date, Some("2023-02-01T00:00:00".to_string()));
+
+        let payload = json!({"requests": [{"instruments": ["XOM"], "fields": [], "parameters": {"SDate": "2002-01-01", "EDate": "2002-02-10"}}]});
+        let f = Failure::from_chunk(&payload, &EkError::NoData("empty response".to_string()));
+        assert_eq!(f.code, None);
+        assert_eq!(f.instruments, vec!["XOM"]);
+        assert_eq!(f.end_date, Some("2002-02-10".to_string()));
+
+        let report = Report { chunks: vec![f.to_owned()], rics: vec![f], ..Report::default() };
+        assert_eq!(report.failed_instruments(), vec!["XOM"]);
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time;
+use log::warn;
+use crate::utils::EkError;
+
+/// Decides which failed requests are sent again and how long to wait in between.
+///
+/// The wait grows exponentially from `base_delay`, is capped at `max_delay` and is spread by
+/// `jitter` (a fraction of the delay) so that concurrent chunks do not retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub base_delay: time::Duration,
+    pub max_delay: time::Duration,
+    /// Fraction of the delay, between 0 and 1, that is randomly added or subtracted
+    pub jitter: f64,
+    /// Server `ErrorCode`s (or HTTP status codes) that are retried
+    pub error_codes: Vec<i64>,
+    /// Retry when the proxy cannot be reached
+    pub retry_connection: bool,
+    /// Retry when a request times out
+    pub retry_timeout: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: time::Duration::from_millis(500),
+            max_delay: time::Duration::from_secs(30),
+            jitter: 0.2,
+            error_codes: vec![400, 429, 500, 503, 2504],
+            retry_connection: true,
+            retry_timeout: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn retryable(&self, e: &EkError) -> bool {
+        match e {
+            EkError::ServerError(code, _) => self.error_codes.contains(code),
+            EkError::Http(e) if e.is_timeout() => self.retry_timeout,
+            EkError::Http(e) => (e.is_connect() || e.is_request()) && self.retry_connection,
+            _ => false
+        }
+    }
+
+    /// Wait before the next attempt, `attempt` being the number of attempts made so far.
+    pub fn delay(&self, attempt: u32) -> time::Duration {
+        let exp = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(exp).min(self.max_delay);
+        let jitter = self.jitter.clamp(0f64, 1f64);
+        if jitter == 0f64 {
+            return delay;
+        }
+        // Uniform factor in [1 - jitter, 1 + jitter]
+        let unit = random() as f64 / u64::MAX as f64;
+        delay.mul_f64(1f64 - jitter + 2f64 * jitter * unit)
+    }
+
+    /// Runs `f` until it succeeds, fails with a non-retryable error or runs out of attempts.
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - Description of the request, used when logging retries
+    /// * `f` - Produces the request future for each attempt
+    ///
+    /// # Returns
+    ///
+    /// The first successful result, or the error of the last attempt
+    pub async fn run<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T, EkError>
+        where F: FnMut() -> Fut,
+              Fut: Future<Output=Result<T, EkError>>
+    {
+        let mut attempt = 0u32;
+        loop {
+            attempt += 1;
+            match f().await {
+                Ok(r) => return Ok(r),
+                Err(e) => {
+                    if attempt >= self.max_attempts || !self.retryable(&e) {
+                        return Err(e);
+                    }
+                    let delay = self.delay(attempt);
+                    warn!("{} failed on attempt {}/{}: {}, retrying in {:?}", what, attempt, self.max_attempts, e, delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: time::Duration::from_millis(1),
+            jitter: 0f64,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            base_delay: time::Duration::from_millis(100),
+            max_delay: time::Duration::from_millis(500),
+            jitter: 0f64,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.delay(1), time::Duration::from_millis(100));
+        assert_eq!(policy.delay(3), time::Duration::from_millis(400));
+        assert_eq!(policy.delay(4), time::Duration::from_millis(500));
+
+        let policy = RetryPolicy { jitter: 0.5, ..policy };
+        for _ in 0..100 {
+            let d = policy.delay(1);
+            assert!(d >= time::Duration::from_millis(50) && d <= time::Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn test_retryable() {
+        let policy = RetryPolicy::default();
+        assert!(policy.retryable(&EkError::ServerError(2504, "Backend error".to_string())));
+        assert!(!policy.retryable(&EkError::ServerError(401, "Unauthorized".to_string())));
+        assert!(!policy.retryable(&EkError::NoData("invalid json".to_string())));
+    }
+
+    #[test]
+    fn test_run_returns_last_error() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut attempts = 0;
+        let res: Result<(), EkError> = rt.block_on(policy().run("Test", || {
+            attempts += 1;
+            let n = attempts;
+            async move { Err(EkError::ServerError(500, format!("attempt {}", n))) }
+        }));
+        assert_eq!(attempts, 5);
+        match res {
+            Err(EkError::ServerError(500, m)) => assert_eq!(m, "attempt 5"),
+            _ => panic!("Expected the last server error"),
+        }
+    }
+
+    #[test]
+    fn test_run_async() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut attempts = 0;
+        let res = rt.block_on(policy().run("Test", || {
+            attempts += 1;
+            let n = attempts;
+            async move {
+                if n < 3 { Err(EkError::ServerError(503, "Service unavailable".to_string())) } else { Ok(n) }
+            }
+        }));
+        assert_eq!(res.ok(), Some(3));
+
+        let mut attempts = 0;
+        let res: Result<(), EkError> = rt.block_on(policy().run("Test", || {
+            attempts += 1;
+            async { Err(EkError::ServerError(401, "Unauthorized".to_string())) }
+        }));
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -1,16 +1,58 @@
+use std::sync::Arc;
+use crate::cache::{Cache, Range};
+use crate::calendar::{Calendar, Weekdays};
 use crate::connection::{Connection, Direction};
-use crate::utils::{clean_string, EkResults, EkError, vstack_diag};
+use crate::report::{Failure, MissingFields, Partial, Report};
+use crate::utils::{clean_string, EkError, parse_timestamp, vstack_diag};
 use chrono::prelude::*;
-use polars::error::PolarsResult;
+use chrono::Duration;
+use std::str::FromStr;
 use polars::frame::DataFrame;
 use polars::prelude::*;
 use serde_json::{json, Value};
 use polars::series::Series;
-use log::{info, error, debug, trace, warn};
+use log::{debug, warn};
 
+/// Response of one chunk, `None` when the server sent nothing for it.
+type Outcome = Result<Option<Value>, EkError>;
+
+/// Window the cache does not hold with the fields missing from it.
+type Gap = (Range, Vec<TsField>);
+
+/// Rows the server returns at most for one request.
+const MAX_ROWS: usize = 3000;
+
+/// Rows a chunk is sized for, one under `MAX_ROWS` so that a response holding `MAX_ROWS` rows
+/// can only mean the server cut it.
+const CHUNK_ROWS: usize = MAX_ROWS - 1;
+
+/// Rounds of follow-up requests sent for truncated chunks.
+const MAX_RESPLITS: usize = 8;
+
+/// Ticks a RIC is assumed to trade per minute of session when sizing tick and TAQ chunks. Busier
+/// RICs fill their chunks, which are then split at the rate they were returned at.
+const TICKS_PER_MINUTE: f64 = 1f64;
+
+/// Parts a missing tick window is split into at most in one round of follow-up requests.
+const MAX_TICK_PARTS: usize = 64;
+
+/// Days of tick and TAQ data the server keeps.
+const TICK_LOOKBACK_DAYS: i64 = 90;
+
+/// Days of intraday bars the server keeps.
+const INTRADAY_LOOKBACK_DAYS: i64 = 366;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Interval {
-    //tick
+    /// Every trade
+    Tick,
+    /// Trades and quotes
+    Taq,
     Minute,
+    FiveMinutes,
+    TenMinutes,
+    ThirtyMinutes,
+    SixtyMinutes,
     Hour,
     Daily,
     Weekly,
@@ -22,7 +64,13 @@ pub enum Interval {
 impl Interval {
     fn as_str(&self) -> &'static str {
         match self {
+            Interval::Tick => { "tick" }
+            Interval::Taq => { "taq" }
             Interval::Minute => { "minute" }
+            Interval::FiveMinutes => { "5minutes" }
+            Interval::TenMinutes => { "10minutes" }
+            Interval::ThirtyMinutes => { "30minutes" }
+            Interval::SixtyMinutes => { "60minutes" }
             Interval::Hour => { "hour" }
             Interval::Daily => { "daily" }
             Interval::Weekly => { "weekly" }
@@ -31,258 +79,1209 @@ impl Interval {
             Interval::Yearly => { "yearly" }
         }
     }
-    pub fn new(v: &str) -> Interval {
-        match v {
-            "minute" => { Interval::Minute }
-            "hour" => { Interval::Hour }
-            "weekly" => { Interval::Weekly }
-            "monthly" => { Interval::Monthly }
-            "quarterly" => { Interval::Quarterly }
-            "yearly" => { Interval::Yearly }
-            _ => { Interval::Daily }
+    /// Length of one bar of an intraday summary, `None` for tick data and daily or longer bars.
+    fn bar(&self) -> Option<Duration> {
+        match self {
+            Interval::Minute => { Some(Duration::minutes(1)) }
+            Interval::FiveMinutes => { Some(Duration::minutes(5)) }
+            Interval::TenMinutes => { Some(Duration::minutes(10)) }
+            Interval::ThirtyMinutes => { Some(Duration::minutes(30)) }
+            Interval::SixtyMinutes | Interval::Hour => { Some(Duration::minutes(60)) }
+            _ => { None }
+        }
+    }
+
+    /// Rows one RIC returns for a calendar day, averaged for weekly and longer intervals.
+    fn per_day(&self) -> Option<f64> {
+        match self {
+            Interval::Weekly => { Some(1f64 / 7f64) }
+            Interval::Monthly => { Some(1f64 / 28f64) }
+            Interval::Quarterly => { Some(1f64 / 89f64) }
+            Interval::Yearly => { Some(1f64 / 365f64) }
+            _ => { None }
         }
     }
+
+    /// Rows one RIC returns on a trading day.
+    fn rows_per_trading_day(&self, calendar: &dyn Calendar) -> f64 {
+        let session = calendar.session();
+        match self {
+            Interval::Tick | Interval::Taq => { session.num_minutes() as f64 * TICKS_PER_MINUTE }
+            Interval::Daily => { 1f64 }
+            i => match i.bar() {
+                Some(bar) => { (session.num_minutes() as f64 / bar.num_minutes() as f64).ceil() }
+                None => { i.per_day().unwrap_or(1f64) * 7f64 }
+            }
+        }
+    }
+
+    /// Rows one RIC returns on `day`.
+    fn rows_on(&self, day: NaiveDate, calendar: &dyn Calendar) -> f64 {
+        match self.per_day() {
+            Some(r) => r,
+            None if calendar.is_trading_day(day) => self.rows_per_trading_day(calendar),
+            None => 0f64
+        }
+    }
+
+    /// Length of one row, the longest time between the bounds of a window and its first or last row
+    /// when the market trades throughout it.
+    pub(crate) fn period(&self) -> Duration {
+        match self {
+            Interval::Tick | Interval::Taq => { self.resolution() }
+            Interval::Daily => { Duration::days(1) }
+            Interval::Weekly => { Duration::days(7) }
+            Interval::Monthly => { Duration::days(31) }
+            Interval::Quarterly => { Duration::days(92) }
+            Interval::Yearly => { Duration::days(366) }
+            i => { i.bar().unwrap_or(Duration::days(1)) }
+        }
+    }
+
+    /// Smallest step between two timestamps, separates consecutive request windows.
+    pub(crate) fn resolution(&self) -> Duration {
+        match self {
+            Interval::Tick | Interval::Taq => { Duration::milliseconds(1) }
+            _ => { Duration::seconds(1) }
+        }
+    }
+
+    /// How far back the server keeps data for the interval, `None` when there is no limit.
+    fn lookback(&self) -> Option<Duration> {
+        match self {
+            Interval::Tick | Interval::Taq => { Some(Duration::days(TICK_LOOKBACK_DAYS)) }
+            i if i.bar().is_some() => { Some(Duration::days(INTRADAY_LOOKBACK_DAYS)) }
+            _ => { None }
+        }
+    }
+}
+
+impl FromStr for Interval {
+    type Err = EkError;
+
+    /// Parses the Eikon interval names, their platform codes (`PT5M`, `P1D`, ...) and common
+    /// abbreviations, ignoring case.
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        match v.trim().to_lowercase().as_str() {
+            "tick" | "ticks" => { Ok(Interval::Tick) }
+            "taq" => { Ok(Interval::Taq) }
+            "minute" | "1minute" | "1min" | "1m" | "pt1m" => { Ok(Interval::Minute) }
+            "5minutes" | "5min" | "5m" | "pt5m" => { Ok(Interval::FiveMinutes) }
+            "10minutes" | "10min" | "10m" | "pt10m" => { Ok(Interval::TenMinutes) }
+            "30minutes" | "30min" | "30m" | "pt30m" => { Ok(Interval::ThirtyMinutes) }
+            "60minutes" | "60min" | "60m" | "pt60m" => { Ok(Interval::SixtyMinutes) }
+            "hour" | "hourly" | "1h" | "pt1h" => { Ok(Interval::Hour) }
+            "daily" | "day" | "d" | "1d" | "p1d" => { Ok(Interval::Daily) }
+            "weekly" | "week" | "w" | "1w" | "p1w" | "p7d" => { Ok(Interval::Weekly) }
+            "monthly" | "month" | "mo" | "p1m" => { Ok(Interval::Monthly) }
+            "quarterly" | "quarter" | "q" | "p3m" => { Ok(Interval::Quarterly) }
+            "yearly" | "year" | "annual" | "y" | "p1y" | "p12m" => { Ok(Interval::Yearly) }
+            _ => { Err(EkError::UnknownFrequency(v.to_string())) }
+        }
+    }
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Checks that the window is not empty and lies within what the server keeps for `frq`.
+fn check_window(frq: Interval, start_date: NaiveDateTime, end_date: NaiveDateTime, now: NaiveDateTime) -> Result<(), EkError> {
+    if start_date >= end_date {
+        return Err(EkError::DateError(format!("Start date {} is not before end date {}", start_date, end_date)));
+    }
+    if let Some(lookback) = frq.lookback() {
+        let first = now - lookback;
+        if start_date < first {
+            return Err(EkError::DateError(format!(
+                "{} data is only kept for {} days, start date {} is before {}",
+                frq, lookback.num_days(), start_date, first.format("%F %T")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Field of a TimeSeries request.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TsField {
+    /// Every field the server has for the interval, sent as `*`
+    All,
+    Timestamp,
+    Open,
+    High,
+    Low,
+    Close,
+    Volume,
+    Count,
+    Value,
+    /// Any other field code, sent as is
+    Other(String),
 }
 
+impl TsField {
+    pub fn as_str(&self) -> &str {
+        match self {
+            TsField::All => { "*" }
+            TsField::Timestamp => { "TIMESTAMP" }
+            TsField::Open => { "OPEN" }
+            TsField::High => { "HIGH" }
+            TsField::Low => { "LOW" }
+            TsField::Close => { "CLOSE" }
+            TsField::Volume => { "VOLUME" }
+            TsField::Count => { "COUNT" }
+            TsField::Value => { "VALUE" }
+            TsField::Other(s) => { s.as_str() }
+        }
+    }
+}
+
+impl From<&str> for TsField {
+    fn from(v: &str) -> Self {
+        match v.to_uppercase().as_str() {
+            "*" => { TsField::All }
+            "TIMESTAMP" => { TsField::Timestamp }
+            "OPEN" => { TsField::Open }
+            "HIGH" => { TsField::High }
+            "LOW" => { TsField::Low }
+            "CLOSE" => { TsField::Close }
+            "VOLUME" => { TsField::Volume }
+            "COUNT" => { TsField::Count }
+            "VALUE" => { TsField::Value }
+            _ => { TsField::Other(v.to_string()) }
+        }
+    }
+}
+
+impl std::fmt::Display for TsField {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
 
 pub struct TimeSeries {
     connection: Connection,
+    calendar: Arc<dyn Calendar>,
+    cache: Option<Cache>,
 }
 
 impl TimeSeries {
     pub fn new(c: Connection) -> Self
     {
         Self {
-            connection: c
+            connection: c,
+            calendar: Arc::new(Weekdays),
+            cache: None,
         }
     }
+
+    /// Sets the calendar the requests are sized with, `Weekdays` by default.
+    pub fn set_calendar(&mut self, calendar: impl Calendar + 'static) {
+        self.calendar = Arc::new(calendar);
+    }
+
+    /// Serves `get_timeseries` and `get_timeseries_partial` from `cache`, downloading only what it
+    /// does not hold. The raw responses are always downloaded.
+    pub fn set_cache(&mut self, cache: Option<Cache>) {
+        self.cache = cache;
+    }
 }
 
 impl TimeSeries {
+    /// Blocking counterpart of `get_timeseries_async`, must not be called from within an async runtime.
     pub fn get_timeseries(
         &self,
         rics: Vec<String>,
-        fields: Vec<String>,
-        Frq: Interval,
-        SDate: NaiveDateTime,
-        EDate: NaiveDateTime,
-    ) -> EkResults {
-        let direction = Direction::TimeSeries;
+        fields: Vec<TsField>,
+        frq: Interval,
+        start_date: NaiveDateTime,
+        end_date: NaiveDateTime,
+    ) -> Result<DataFrame, EkError> {
+        self.connection.runtime()?.block_on(self.get_timeseries_async(rics, fields, frq, start_date, end_date))
+    }
+
+    /// Blocking counterpart of `get_timeseries_raw_async`, must not be called from within an async runtime.
+    pub fn get_timeseries_raw(
+        &self,
+        rics: Vec<String>,
+        fields: Vec<TsField>,
+        frq: Interval,
+        start_date: NaiveDateTime,
+        end_date: NaiveDateTime,
+    ) -> Result<Vec<Value>, EkError> {
+        self.connection.runtime()?.block_on(self.get_timeseries_raw_async(rics, fields, frq, start_date, end_date))
+    }
+
+    /// Blocking counterpart of `get_timeseries_partial_async`, must not be called from within an async runtime.
+    pub fn get_timeseries_partial(
+        &self,
+        rics: Vec<String>,
+        fields: Vec<TsField>,
+        frq: Interval,
+        start_date: NaiveDateTime,
+        end_date: NaiveDateTime,
+    ) -> Result<Partial<DataFrame>, EkError> {
+        self.connection.runtime()?.block_on(self.get_timeseries_partial_async(rics, fields, frq, start_date, end_date))
+    }
+
+    /// Downloads the time series and combines every chunk into one DataFrame.
+    ///
+    /// Fails as soon as one chunk fails, and leaves out RICs the server returned an error for; use
+    /// `get_timeseries_partial_async` to keep what succeeded and learn what did not. Requested
+    /// fields a RIC did not return are only logged.
+    pub async fn get_timeseries_async(
+        &self,
+        rics: Vec<String>,
+        fields: Vec<TsField>,
+        frq: Interval,
+        start_date: NaiveDateTime,
+        end_date: NaiveDateTime,
+    ) -> Result<DataFrame, EkError> {
+        if let Some(cache) = &self.cache {
+            let res = self.get_cached(cache, rics, fields, frq, start_date, end_date, true).await?;
+            return res.data.ok_or(EkError::NoDataFrame("No RIC returned any data".to_string()));
+        }
+        let res = self.get_timeseries_raw_async(rics, fields.to_owned(), frq, start_date, end_date).await?;
+        check_fields(&fields, &res);
+        match combine(res)? {
+            None => Err(EkError::NoDataFrame("No RIC returned any data".to_string())),
+            Some(r) => Ok(r)
+        }
+    }
+
+    /// Downloads the time series, keeping the data of every chunk and RIC that succeeded.
+    ///
+    /// # Returns
+    ///
+    /// The combined DataFrame with a report of the chunks and RICs that failed, or an error when
+    /// no request could be made at all
+    pub async fn get_timeseries_partial_async(
+        &self,
+        rics: Vec<String>,
+        fields: Vec<TsField>,
+        frq: Interval,
+        start_date: NaiveDateTime,
+        end_date: NaiveDateTime,
+    ) -> Result<Partial<DataFrame>, EkError> {
+        if let Some(cache) = &self.cache {
+            return self.get_cached(cache, rics, fields, frq, start_date, end_date, false).await;
+        }
+        check_window(frq, start_date, end_date, Utc::now().naive_utc())?;
+        let payloads = groups(rics, &fields, start_date, end_date, frq, self.calendar.as_ref());
+        let res = self.fetch(payloads, frq).await?;
+
+        let mut report = Report::default();
+        let mut responses = Vec::new();
+        for (payload, r) in res.iter() {
+            match r {
+                Err(e) => report.chunks.push(Failure::from_chunk(payload, e)),
+                Ok(None) => {}
+                Ok(Some(r)) => {
+                    for entry in r["timeseriesData"].as_array().unwrap_or(&Vec::new()) {
+                        if entry["statusCode"] != "Normal" {
+                            report.rics.push(Failure::from_ric(payload, entry));
+                        }
+                    }
+                    responses.push(r.to_owned())
+                }
+            }
+        }
+        for f in report.chunks.iter().chain(report.rics.iter()) {
+            warn!("Could not download {:?} ({:?} - {:?}): {}", f.instruments, f.start_date, f.end_date, f.message);
+        }
+        report.missing_fields = check_fields(&fields, &responses);
+        Ok(Partial { data: combine(responses)?, report })
+    }
+
+    /// Downloads the time series and returns the JSON response of every chunk as sent by the server.
+    pub async fn get_timeseries_raw_async(
+        &self,
+        rics: Vec<String>,
+        fields: Vec<TsField>,
+        frq: Interval,
+        start_date: NaiveDateTime,
+        end_date: NaiveDateTime,
+    ) -> Result<Vec<Value>, EkError> {
+        check_window(frq, start_date, end_date, Utc::now().naive_utc())?;
         // Creating the payloads
-        let payloads = groups(rics, fields, SDate, EDate, Frq);
-        let res = match self.connection.send_request_async_handler(payloads, direction) {
-            Ok(r) => r,
-            Err(e) => return EkResults::Err(e),
-        };
+        let payloads = groups(rics, &fields, start_date, end_date, frq, self.calendar.as_ref());
+        let mut res = Vec::new();
+        for (_, r) in self.fetch(payloads, frq).await? {
+            if let Some(v) = r? {
+                res.push(v)
+            }
+        }
         if res.is_empty() {
-            return EkResults::Err(EkError::NoData("No data returned from Refinitiv".to_string()));
+            return Err(EkError::NoData("No data returned from Refinitiv".to_string()));
         }
+        Ok(res)
+    }
 
+    /// Downloads what the cache does not hold, stores it, then reads the whole window from the cache.
+    ///
+    /// The RICs missing the same fields over the same window are requested together. A truncated
+    /// response is only recorded as held between its first and last rows, and rows less than one
+    /// interval old are not recorded as held at all, so they are downloaded again next time.
+    ///
+    /// # Arguments
+    ///
+    /// * `strict` - Fail on the first chunk that fails instead of reporting it
+    #[allow(clippy::too_many_arguments)]
+    async fn get_cached(
+        &self,
+        cache: &Cache,
+        rics: Vec<String>,
+        fields: Vec<TsField>,
+        frq: Interval,
+        start_date: NaiveDateTime,
+        end_date: NaiveDateTime,
+        strict: bool,
+    ) -> Result<Partial<DataFrame>, EkError> {
+        let now = Utc::now().naive_utc();
+        check_window(frq, start_date, end_date, now)?;
+        let mut requests: Vec<(Gap, Vec<String>)> = Vec::new();
+        for ric in rics.iter() {
+            let mut missing: Vec<Gap> = Vec::new();
+            for field in fields.iter() {
+                for window in cache.missing(ric, frq, field, start_date, end_date)? {
+                    match missing.iter_mut().find(|(w, _)| *w == window) {
+                        Some((_, f)) => f.push(field.to_owned()),
+                        None => missing.push((window, vec![field.to_owned()]))
+                    }
+                }
+            }
+            for key in missing {
+                match requests.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, r)) => r.push(ric.to_owned()),
+                    None => requests.push((key, vec![ric.to_owned()]))
+                }
+            }
+        }
 
-        let mut df_vec = Vec::new();
-
-        for response in res {
-            match to_dataframe(response) {
-                Err(e) => return EkResults::Err(e),
-                Ok(r) => {
-                    match r {
-                        None => {}
-                        Some(r) => df_vec.push(r)
+        let mut report = Report::default();
+        let mut responses = Vec::new();
+        let held_until = now - frq.period();
+        for (((s, e), request_fields), request_rics) in requests {
+            debug!("Downloading {:?} for {:?} from {} to {}", request_fields, request_rics, s, e);
+            let payloads = groups(request_rics, &request_fields, s, e, frq, self.calendar.as_ref());
+            let mut received = Vec::new();
+            for (payload, r) in self.fetch(payloads, frq).await? {
+                let r = match r {
+                    Err(e) if strict => return Err(e),
+                    Err(e) => {
+                        report.chunks.push(Failure::from_chunk(&payload, &e));
+                        continue;
+                    }
+                    Ok(None) => continue,
+                    Ok(Some(r)) => r
+                };
+                let window = match (
+                    serde_json::from_value::<NaiveDateTime>(payload["startdate"].to_owned()),
+                    serde_json::from_value::<NaiveDateTime>(payload["enddate"].to_owned()),
+                ) {
+                    (Ok(s), Ok(e)) => (s, e),
+                    _ => continue
+                };
+                for entry in r["timeseriesData"].as_array().unwrap_or(&Vec::new()) {
+                    if entry["statusCode"] != "Normal" {
+                        report.rics.push(Failure::from_ric(&payload, entry));
+                        continue;
+                    }
+                    let window = match truncated(&r) {
+                        false => Some(window),
+                        true => returned_window(entry)
+                    };
+                    if let Some(window) = window {
+                        received.push((window, entry.to_owned()));
                     }
                 }
+                responses.push(r);
+            }
+            cache.store(frq, &request_fields, held_until, &received)?;
+        }
+        for f in report.chunks.iter().chain(report.rics.iter()) {
+            warn!("Could not download {:?} ({:?} - {:?}): {}", f.instruments, f.start_date, f.end_date, f.message);
+        }
+        report.missing_fields = check_fields(&fields, &responses);
+
+        let mut held = Vec::with_capacity(rics.len());
+        for ric in rics.iter() {
+            if let Some(entry) = cache.load(ric, frq, &fields, start_date, end_date)? {
+                held.push(json!({"timeseriesData": [entry]}));
             }
         }
+        Ok(Partial { data: combine(held)?, report })
+    }
 
-        let mut df = df_vec[0].to_owned();
+    /// Sends the payloads, then requests again what is missing from the responses that were truncated.
+    ///
+    /// # Returns
+    ///
+    /// Every payload that was finally used with its outcome, in the order of the original payloads
+    async fn fetch(
+        &self,
+        payloads: Vec<Value>,
+        frq: Interval,
+    ) -> Result<Vec<(Value, Outcome)>, EkError> {
+        let mut chunks: Vec<(Value, Option<Outcome>)> = payloads.into_iter()
+            .map(|p| (p, None))
+            .collect();
+        for round in 0..=MAX_RESPLITS {
+            let pending = chunks.iter()
+                .filter(|(_, r)| r.is_none())
+                .map(|(p, _)| p.to_owned())
+                .collect::<Vec<Value>>();
+            if pending.is_empty() {
+                break;
+            }
+            let mut res = self.connection.send_requests_each(pending, Direction::TimeSeries).await?.into_iter();
+            for (_, r) in chunks.iter_mut().filter(|(_, r)| r.is_none()) {
+                *r = res.next();
+            }
+            if round == MAX_RESPLITS {
+                break;
+            }
 
-        for (i, n_df) in df_vec.into_iter().enumerate() {
-            if i != 0 {
-                if n_df.shape().1 > df.shape().1 {
-                    df = vstack_diag(df, n_df);
-                } else if n_df.shape().1 < df.shape().1 {
-                    df = vstack_diag(n_df, df);
-                } else {
-                    df = df.vstack(&n_df).unwrap();
+            let mut next = Vec::with_capacity(chunks.len());
+            for (p, r) in chunks {
+                let gaps = match &r {
+                    Some(Ok(Some(v))) if truncated(v) => Some(follow_ups(&p, v, frq, self.calendar.as_ref())),
+                    _ => None
+                };
+                match gaps {
+                    None => next.push((p, r)),
+                    Some(Some((before, after))) => {
+                        debug!("Response for {} - {} was truncated, requesting {} missing windows",
+                            p["startdate"], p["enddate"], before.len() + after.len());
+                        next.extend(before.into_iter().map(|f| (f, None)));
+                        next.push((p, r));
+                        next.extend(after.into_iter().map(|f| (f, None)));
+                    }
+                    // Without timestamps the missing part is unknown, the whole chunk is requested again in halves
+                    Some(None) => match split_payload(&p, frq) {
+                        None => next.push((p, r)),
+                        Some((a, b)) => {
+                            debug!("Response for {} - {} was truncated, splitting it", p["startdate"], p["enddate"]);
+                            next.push((a, None));
+                            next.push((b, None));
+                        }
+                    }
                 }
             }
+            chunks = next;
         }
-        EkResults::DF(df)
+        Ok(chunks.into_iter()
+            .map(|(p, r)| (p, r.unwrap_or(Ok(None))))
+            .collect())
     }
 }
 
+/// Requested fields the server did not return for a RIC.
+///
+/// # Returns
+///
+/// The missing field names, empty when everything was returned or everything was requested
+fn missing_fields(requested: &[TsField], entry: &Value) -> Vec<String> {
+    let returned = entry["fields"].as_array()
+        .map(|f| f.iter().filter_map(|v| v["name"].as_str()).collect::<Vec<&str>>())
+        .unwrap_or_default();
+    requested.iter()
+        .filter(|f| **f != TsField::All)
+        .filter(|f| !returned.iter().any(|r| r.eq_ignore_ascii_case(f.as_str())))
+        .map(|f| f.to_string())
+        .collect()
+}
+
+/// Missing fields of every RIC that returned data, keeping only RICs that miss something.
+fn check_fields(requested: &[TsField], responses: &[Value]) -> Vec<MissingFields> {
+    let mut res = Vec::new();
+    for r in responses {
+        for entry in r["timeseriesData"].as_array().map(|e| e.as_slice()).unwrap_or_default() {
+            if entry["statusCode"] != "Normal" {
+                continue;
+            }
+            let fields = missing_fields(requested, entry);
+            if !fields.is_empty() {
+                let ric = clean_string(entry["ric"].to_string());
+                warn!("{} did not return {}", ric, fields.join(", "));
+                res.push(MissingFields { ric, fields });
+            }
+        }
+    }
+    res
+}
+
+/// Combines the responses of every chunk into one DataFrame, `None` when no RIC returned data.
+fn combine(res: Vec<Value>) -> Result<Option<DataFrame>, EkError> {
+    let mut df_vec = Vec::new();
+    for response in res {
+        if let Some(r) = to_dataframe(response)? {
+            df_vec.push(r)
+        }
+    }
+
+    let mut df_iter = df_vec.into_iter();
+    let mut df = match df_iter.next() {
+        None => return Ok(None),
+        Some(r) => r
+    };
+    for n_df in df_iter {
+        df = vstack_diag(df, n_df)?;
+    }
+    // Columns only later chunks returned were added after the RIC column
+    let mut names = df.get_column_names_owned();
+    names.sort_by_key(|n| n == "RIC");
+    Ok(Some(df.select(names)?))
+}
+
 /// Divides the request into smaller chunks that adhere to the maximum number of rows and companies
 /// that can be requested at once.
 ///
+/// Chunks follow the trading days of `calendar` so that each one stays under `MAX_ROWS`, only tick
+/// data can still overflow as its row count is an estimate.
+///
 /// # Arguments
 ///
 /// * `rics` - A vector of RICs
 /// * `fields` - A vector of fields
-/// * `SDate` - Start date
-/// * `EDate` - End date
-/// * `Frq` - Frequency
+/// * `start_date` - Start date
+/// * `end_date` - End date
+/// * `frq` - Frequency
+/// * `calendar` - Trading days and session length of the market
 ///
 /// # Returns
 ///
 /// A vector of payloads that can be sent to the Eikon API
 fn groups(
     rics: Vec<String>,
-    fields: Vec<String>,
-    SDate: NaiveDateTime,
-    EDate: NaiveDateTime,
-    Frq: Interval,
+    fields: &[TsField],
+    start_date: NaiveDateTime,
+    end_date: NaiveDateTime,
+    frq: Interval,
+    calendar: &dyn Calendar,
 ) -> Vec<Value> {
-    let trading_days: usize = 252;
-    let max_rows: usize = 3000;
     let max_companies: usize = 300;
-    let period = EDate.signed_duration_since(SDate);
-    let rows_pr = match Frq {
-        Interval::Minute => { (period.num_minutes() as f32 / 2f32).ceil() as usize }
-        Interval::Hour => { (period.num_hours() as f32 / 2f32).ceil() as usize }
-        Interval::Daily => { ((trading_days as f32 / 365f32) * period.num_days() as f32).ceil() as usize }
-        Interval::Weekly => { (period.num_weeks() as f32).ceil() as usize }
-        Interval::Monthly => { ((period.num_days() as f32 / 365f32) * 12f32).ceil() as usize }
-        Interval::Quarterly => { ((period.num_days() as f32 / 365f32) * 4f32).ceil() as usize }
-        Interval::Yearly => { (period.num_days() as f32 / 365f32).ceil() as usize }
-    };
-    debug!("Rows pr: {}", rows_pr);
+    if rics.is_empty() {
+        return Vec::new();
+    }
 
-    let ric_group_size = if rics.len() > max_companies { max_companies } else { rics.len() };
+    let ric_group_size = ((CHUNK_ROWS as f64 / frq.rows_per_trading_day(calendar)).floor() as usize)
+        .clamp(1, max_companies)
+        .min(rics.len());
     debug!("Ric group size: {}", ric_group_size);
 
-    let time_groups = ((rows_pr as f32 * ric_group_size as f32) / max_rows as f32).ceil() as usize;
-    debug!("Time group: {}", time_groups);
+    let time_groups = windows(start_date, end_date, frq, calendar, ric_group_size);
+    debug!("Time group: {}", time_groups.len());
 
-    let time_groups = create_interval(time_groups, SDate, EDate);
     let mut payloads: Vec<Value> = Vec::new();
     for ric_group in rics.chunks(ric_group_size) {
-        for (Sd, Ed) in time_groups.iter() {
+        for (sd, ed) in time_groups.iter() {
             payloads.push(assemble_payload(
                 ric_group.into_vec(),
-                &fields,
-                Frq.as_str(),
-                Sd,
-                Ed,
+                fields,
+                frq.as_str(),
+                sd,
+                ed,
             ));
         }
     }
     payloads
 }
 
+/// Splits the window at day boundaries so that `rics` RICs fit in `CHUNK_ROWS` in every part.
+///
+/// A single day holding more rows than that is split further into equal parts.
+fn windows(
+    start_date: NaiveDateTime,
+    end_date: NaiveDateTime,
+    frq: Interval,
+    calendar: &dyn Calendar,
+    rics: usize,
+) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let max_rows = CHUNK_ROWS as f64;
+    // A partial week, month, ... at the start of a window is one more row
+    let base = if frq.bar().is_none() && frq.per_day().is_some() { rics as f64 } else { 0f64 };
+
+    let mut res: Vec<(NaiveDateTime, NaiveDateTime)> = Vec::new();
+    let mut window_start = start_date;
+    let mut rows = base;
+    let mut day = start_date.date();
+    while day <= end_date.date() {
+        let day_start = day.and_hms_opt(0, 0, 0).unwrap_or(start_date).max(start_date);
+        let next_day = match day.succ_opt() {
+            None => break,
+            Some(r) => r
+        };
+        let day_end = next_day.and_hms_opt(0, 0, 0).unwrap_or(end_date).min(end_date);
+        let day_rows = frq.rows_on(day, calendar) * rics as f64;
+
+        if day_rows > max_rows {
+            // Close the open window and cut the day itself into parts
+            if window_start < day_start {
+                res.push((window_start, day_start - frq.resolution()));
+            }
+            let parts = (day_rows / max_rows).ceil() as usize;
+            // Parts share their bounds, each one stops just before the next starts
+            res.extend(create_interval(parts, day_start, day_end, frq.bar())
+                .into_iter()
+                .map(|(s, e)| (s, if e < end_date { e - frq.resolution() } else { e })));
+            window_start = day_end;
+            rows = base;
+        } else if rows + day_rows > max_rows && window_start < day_start {
+            res.push((window_start, day_start - frq.resolution()));
+            window_start = day_start;
+            rows = base + day_rows;
+        } else {
+            rows += day_rows;
+        }
+        day = next_day;
+    }
+    if window_start < end_date || res.is_empty() {
+        res.push((window_start, end_date));
+    }
+    res
+}
+
+/// Whether the server cut the response at its row limit.
+fn truncated(res: &Value) -> bool {
+    let rows: usize = res["timeseriesData"].as_array()
+        .map(|e| e.iter().map(|r| r["dataPoints"].as_array().map(|d| d.len()).unwrap_or_default()).sum())
+        .unwrap_or_default();
+    rows >= MAX_ROWS
+}
+
+/// First and last timestamp of a `timeseriesData` entry, `None` when it has no rows or no `TIMESTAMP`.
+fn returned_window(entry: &Value) -> Option<(NaiveDateTime, NaiveDateTime)> {
+    let index = entry["fields"].as_array()?
+        .iter()
+        .position(|f| f["name"] == "TIMESTAMP")?;
+    let timestamps = entry["dataPoints"].as_array()?
+        .iter()
+        .filter_map(|row| row[index].as_str().and_then(parse_timestamp))
+        .collect::<Vec<NaiveDateTime>>();
+    Some((*timestamps.iter().min()?, *timestamps.iter().max()?))
+}
+
+/// Payloads for the parts of the window a truncated response did not cover.
+///
+/// Each RIC is compared on its own: the part between `startdate` and its first timestamp and the
+/// part between its last timestamp and `enddate` are requested again when `calendar` expects at
+/// least one row in them, so that weekends and holidays at the edges are not requested. RICs that
+/// returned no rows are requested for the whole window.
+///
+/// Tick and TAQ windows are sized for a quiet RIC, so the missing parts of a busy one are split
+/// further at the rate its response returned ticks, rather than requested one chunk per round.
+///
+/// # Returns
+///
+/// The payloads for windows before and after the returned data, or `None` when the timestamps of
+/// the response cannot be read
+fn follow_ups(payload: &Value, res: &Value, frq: Interval, calendar: &dyn Calendar) -> Option<(Vec<Value>, Vec<Value>)> {
+    let start_date = serde_json::from_value::<NaiveDateTime>(payload["startdate"].to_owned()).ok()?;
+    let end_date = serde_json::from_value::<NaiveDateTime>(payload["enddate"].to_owned()).ok()?;
+    let entries = res["timeseriesData"].as_array()?;
+
+    let follow_up = |ric: &Value, s: NaiveDateTime, e: NaiveDateTime| {
+        let mut f = payload.to_owned();
+        f["rics"] = json!([ric]);
+        f["startdate"] = json!(s);
+        f["enddate"] = json!(e);
+        f
+    };
+    let tick = matches!(frq, Interval::Tick | Interval::Taq);
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    for ric in payload["rics"].as_array()? {
+        let entry = entries.iter().find(|e| e["ric"] == *ric);
+        if entry.map(|e| e["statusCode"] != "Normal").unwrap_or_default() {
+            // Already reported as a failed RIC
+            continue;
+        }
+        let rows = entry.and_then(|e| e["dataPoints"].as_array()).map(|d| d.len()).unwrap_or_default();
+        if rows == 0 {
+            after.push(follow_up(ric, start_date, end_date));
+            continue;
+        }
+        let (first, last) = returned_window(entry?)?;
+        let rate = rows as f64 / (last - first).num_seconds().max(1) as f64;
+        let parts = |s: NaiveDateTime, e: NaiveDateTime| match tick {
+            true => tick_parts(s, e, rate, frq),
+            false => vec![(s, e)]
+        };
+        if expected_rows(frq, start_date, first - frq.period(), calendar) >= 1f64 {
+            before.extend(parts(start_date, first - frq.resolution()).into_iter().map(|(s, e)| follow_up(ric, s, e)));
+        }
+        if expected_rows(frq, last + frq.period(), end_date, calendar) >= 1f64 {
+            after.extend(parts(last + frq.resolution(), end_date).into_iter().map(|(s, e)| follow_up(ric, s, e)));
+        }
+    }
+    Some((before, after))
+}
+
+/// Cuts a tick window into parts of about `CHUNK_ROWS` rows at `rate` rows a second, each part
+/// ending just before the next starts.
+fn tick_parts(start_date: NaiveDateTime, end_date: NaiveDateTime, rate: f64, frq: Interval) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let rows = (end_date - start_date).num_milliseconds() as f64 / 1000f64 * rate;
+    let parts = ((rows / CHUNK_ROWS as f64).ceil() as usize).clamp(1, MAX_TICK_PARTS);
+    create_interval(parts, start_date, end_date, None)
+        .into_iter()
+        .map(|(s, e)| (s, if e < end_date { e - frq.resolution() } else { e }))
+        .collect()
+}
+
+/// Rows one RIC is expected to have from `start_date` to `end_date`.
+///
+/// Intraday rows are spread over the session of each trading day, daily and longer rows are
+/// counted on the days that start inside the window.
+fn expected_rows(frq: Interval, start_date: NaiveDateTime, end_date: NaiveDateTime, calendar: &dyn Calendar) -> f64 {
+    let intraday = frq.bar().is_some() || matches!(frq, Interval::Tick | Interval::Taq);
+    let session = calendar.session().num_seconds().max(1) as f64;
+    let mut rows = 0f64;
+    let mut day = start_date.date();
+    while day <= end_date.date() {
+        let next_day = match day.succ_opt() {
+            None => break,
+            Some(r) => r
+        };
+        let (day_start, day_end) = match (day.and_hms_opt(0, 0, 0), next_day.and_hms_opt(0, 0, 0)) {
+            (Some(s), Some(e)) => (s, e),
+            _ => break
+        };
+        if intraday {
+            let overlap = (day_end.min(end_date) - day_start.max(start_date)).num_seconds().max(0) as f64;
+            rows += frq.rows_on(day, calendar) * (overlap / session).min(1f64);
+        } else if day_start >= start_date {
+            rows += frq.rows_on(day, calendar);
+        }
+        day = next_day;
+    }
+    rows
+}
+
+/// Splits a payload in two, by RICs when it has several and otherwise by time.
+///
+/// # Returns
+///
+/// `None` when the payload holds a single RIC over a window that cannot be split any further
+fn split_payload(payload: &Value, frq: Interval) -> Option<(Value, Value)> {
+    let rics = payload["rics"].as_array()?;
+    if rics.len() > 1 {
+        let (a, b) = rics.split_at(rics.len() / 2);
+        let mut first = payload.to_owned();
+        let mut second = payload.to_owned();
+        first["rics"] = json!(a);
+        second["rics"] = json!(b);
+        return Some((first, second));
+    }
+    let start_date = serde_json::from_value::<NaiveDateTime>(payload["startdate"].to_owned()).ok()?;
+    let end_date = serde_json::from_value::<NaiveDateTime>(payload["enddate"].to_owned()).ok()?;
+    let smallest = frq.bar().unwrap_or(frq.resolution()) * 2;
+    if end_date - start_date < smallest {
+        return None;
+    }
+    let halves = create_interval(2, start_date, end_date, frq.bar());
+    if halves.len() != 2 {
+        return None;
+    }
+    let mut first = payload.to_owned();
+    let mut second = payload.to_owned();
+    first["enddate"] = json!(halves[0].1 - frq.resolution());
+    second["startdate"] = json!(halves[1].0);
+    Some((first, second))
+}
+
 fn assemble_payload(
     rics: Vec<String>,
-    fields: &Vec<String>,
-    Frq: &str,
-    SDate: &NaiveDateTime,
-    EDate: &NaiveDateTime,
+    fields: &[TsField],
+    frq: &str,
+    start_date: &NaiveDateTime,
+    end_date: &NaiveDateTime,
 ) -> Value {
     let value = json!(
             {
                 "rics": rics,
-                "fields": fields,
-                "interval": Frq,
-                "startdate": SDate,
-                "enddate": EDate
+                "fields": fields.iter().map(|f| f.as_str()).collect::<Vec<&str>>(),
+                "interval": frq,
+                "startdate": start_date,
+                "enddate": end_date
             }
 
         );
     value
 }
 
+/// Splits the window into `groups` consecutive sub-windows.
+///
+/// With a `bar` length the boundaries fall on whole bars counted from `start_date`, so that no bar
+/// is split between two requests.
 fn create_interval(
     groups: usize,
-    SDate: NaiveDateTime,
-    EDate: NaiveDateTime,
+    start_date: NaiveDateTime,
+    end_date: NaiveDateTime,
+    bar: Option<Duration>,
 ) -> Vec<(NaiveDateTime, NaiveDateTime)> {
     let mut intervals: Vec<(NaiveDateTime, NaiveDateTime)> = Vec::with_capacity(groups);
-    let dur = EDate.signed_duration_since(SDate) / groups as i32;
-    let mut s = SDate;
-    for _ in 0..groups {
-        if intervals.is_empty() {
-            s = SDate;
-        } else {
-            (_, s) = intervals.last()
-                .unwrap()
-                .to_owned();
+    let mut dur = end_date.signed_duration_since(start_date) / groups.max(1) as i32;
+    if let Some(bar) = bar {
+        let bars = (dur.num_seconds() / bar.num_seconds().max(1)).max(1) as i32;
+        dur = bar * bars;
+    }
+    for i in 0..groups {
+        let s = match intervals.last() {
+            None => start_date,
+            Some((_, e)) => e.to_owned()
+        };
+        if s >= end_date {
+            break;
         }
-        intervals.push((s, if s + dur > EDate { EDate } else { s + dur }))
+        // The last window always ends at end_date
+        let e = if i + 1 == groups || s + dur > end_date { end_date } else { s + dur };
+        intervals.push((s, e))
     }
     intervals
 }
 
-fn fetch_headers(json_like: &Value) -> Option<Vec<String>> {
-    // println!("{}", json_like);
+/// Polars type of a TimeSeries column, read from the `type` the server sends with every field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColumnType {
+    Datetime,
+    Float,
+    Int,
+    Utf8,
+}
+
+impl ColumnType {
+    fn new(name: &str, kind: &str) -> Self {
+        if name == "TIMESTAMP" {
+            return ColumnType::Datetime;
+        }
+        match kind.to_lowercase().as_str() {
+            "datetime" | "date" => { ColumnType::Datetime }
+            "double" | "float" | "decimal" | "number" => { ColumnType::Float }
+            "long" | "int" | "integer" => { ColumnType::Int }
+            _ => { ColumnType::Utf8 }
+        }
+    }
+
+    /// Builds the column from the JSON values, values that are null or cannot be read become nulls.
+    fn series(&self, name: &str, values: &[&Value]) -> Series {
+        match self {
+            ColumnType::Datetime => {
+                let v = values.iter().map(|v| v.as_str().and_then(parse_timestamp)).collect::<Vec<Option<NaiveDateTime>>>();
+                DatetimeChunked::from_naive_datetime_options(name, v, TimeUnit::Milliseconds).into_series()
+            }
+            ColumnType::Float => {
+                let v = values.iter().map(|v| v.as_f64()).collect::<Vec<Option<f64>>>();
+                Series::new(name, v)
+            }
+            ColumnType::Int => {
+                let v = values.iter()
+                    .map(|v| v.as_i64().or_else(|| v.as_f64().filter(|f| f.fract() == 0f64).map(|f| f as i64)))
+                    .collect::<Vec<Option<i64>>>();
+                Series::new(name, v)
+            }
+            ColumnType::Utf8 => {
+                let v = values.iter()
+                    .map(|v| match v {
+                        Value::Null => None,
+                        Value::String(s) => Some(s.to_owned()),
+                        v => Some(v.to_string())
+                    })
+                    .collect::<Vec<Option<String>>>();
+                Series::new(name, v)
+            }
+        }
+    }
+}
+
+/// Names and types of the fields of a RIC, `None` when the server returned an error for it.
+fn fetch_headers(json_like: &Value) -> Option<Vec<(String, ColumnType)>> {
     if json_like["statusCode"] == "Normal" {
-        let mut field_type: Vec<String> = Vec::new();
-        for value in json_like["fields"]
-            .as_array()
-            .expect("Could not iter rows") {
-            field_type.push(clean_string(value["name"].to_string()));
-        }
-        field_type.push(String::from("RIC"));
-        Some(field_type)
+        let headers = json_like["fields"]
+            .as_array()?
+            .iter()
+            .map(|value| {
+                let name = clean_string(value["name"].to_string());
+                let kind = ColumnType::new(&name, value["type"].as_str().unwrap_or_default());
+                (name, kind)
+            })
+            .collect();
+        Some(headers)
     } else {
         None
     }
 }
 
 fn to_dataframe(json_like: Value) -> Result<Option<DataFrame>, EkError> {
-    // TODO: Should implement a way to run it again if it fails.
-
-    let mut found = false;
-    let mut headers: Vec<String> = Vec::new();
+    let entries = match json_like["timeseriesData"].as_array() {
+        None => return Ok(None),
+        Some(r) => r
+    };
 
-    for request in json_like["timeseriesData"].as_array().unwrap() {
-        match fetch_headers(request) {
-            None => continue,
-            Some(r) => {
-                headers = r;
-                found = true;
-                break;
+    // Columns in the order they first appear, RICs may not all return the same fields
+    let mut headers: Vec<(String, ColumnType)> = Vec::new();
+    let mut rics = Vec::new();
+    for request in entries {
+        if let Some(r) = fetch_headers(request) {
+            for h in r.iter() {
+                if !headers.iter().any(|(name, _)| *name == h.0) {
+                    headers.push(h.to_owned());
+                }
             }
+            rics.push((request, r));
         }
     }
 
-    if !found {
+    if rics.is_empty() {
         return Ok(None);
     }
 
-    let mut res: Vec<Series> = Vec::with_capacity(headers.len());
-    for i in 0..headers.len() {
-        let mut ser_string: Vec<String> = Vec::new();
-        // let mut ser_f64: Vec<f64> = Vec::new();
-        // let mut numeric: bool = false;
-        for ric in json_like["timeseriesData"].as_array().unwrap() {
-            if ric["statusCode"] != "Normal" {
-                continue;
-            }
-            for row in ric["dataPoints"]
-                .as_array()
-                .expect("Could not convert json_like to Array (TimeSeries::to_dataframe)") {
-                if headers[i] == String::from("RIC") {
-                    ser_string.push(clean_string(ric["ric"].to_string()));
-                } else {
-                    ser_string.push(clean_string(row[i].to_string()))
-                    // numeric = row[i].is_number();
-                    // match numeric {
-                    //     true => ser_f64.push(match row[i].as_f64() {
-                    //         None => return Err(EkError::Error("Could not parse column as f64".to_string())),
-                    //         Some(r) => r
-                    //     }),
-                    //     false => ser_string.push(clean_string(row[i].to_string()))
-                    // }
-                }
+    let no_rows = Vec::new();
+    let mut res: Vec<Series> = Vec::with_capacity(headers.len() + 1);
+    for (name, kind) in headers.iter() {
+        let mut values: Vec<&Value> = Vec::new();
+        for (ric, fields) in rics.iter() {
+            let i = fields.iter().position(|(n, _)| n == name);
+            for row in ric["dataPoints"].as_array().unwrap_or(&no_rows) {
+                values.push(match i {
+                    Some(i) => &row[i],
+                    None => &Value::Null
+                });
             }
         }
-        res.push(Series::new(headers[i].as_str(), ser_string))
-        // match numeric {
-        //     true => res.push(Series::new(headers[i].as_str(), ser_f64)),
-        //     false => res.push(Series::new(headers[i].as_str(), ser_string))
-        // }
+        res.push(kind.series(name, &values))
     }
-    match DataFrame::new(res) {
-        Ok(r) => { Ok(Some(r)) }
-        Err(e) => { Err(EkError::NoDataFrame("Could not parse as Polars df".to_string())) }
+
+    let mut ric_names: Vec<&str> = Vec::new();
+    for (ric, _) in rics.iter() {
+        let rows = ric["dataPoints"].as_array().map(|r| r.len()).unwrap_or_default();
+        ric_names.extend(std::iter::repeat_n(ric["ric"].as_str().unwrap_or_default(), rows));
     }
+    res.push(Series::new("RIC", ric_names));
+
+    Ok(Some(DataFrame::new(res)?))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_dataframe() {
+        let res = json!({"timeseriesData": [
+            {
+                "ric": "XOM",
+                "statusCode": "Normal",
+                "fields": [{"name": "TIMESTAMP", "type": "DateTime"}, {"name": "CLOSE", "type": "Double"}, {"name": "VOLUME", "type": "Long"}],
+                "dataPoints": [["2023-01-03T00:00:00Z", 108.2, 2000], ["2023-01-04T00:00:00Z", null, null]]
+            },
+            {"ric": "NOPE", "statusCode": "Error", "errorMessage": "Invalid RIC"},
+            {
+                "ric": "GME",
+                "statusCode": "Normal",
+                "fields": [{"name": "TIMESTAMP", "type": "DateTime"}, {"name": "CLOSE", "type": "Double"}],
+                "dataPoints": [["2023-01-03T14:30:00.500Z", 20]]
+            }
+        ]});
+        let df = to_dataframe(res).unwrap().unwrap();
+        assert_eq!(df.get_column_names(), vec!["TIMESTAMP", "CLOSE", "VOLUME", "RIC"]);
+        assert_eq!(df.dtypes(), vec![
+            DataType::Datetime(TimeUnit::Milliseconds, None),
+            DataType::Float64,
+            DataType::Int64,
+            DataType::Utf8,
+        ]);
+        assert_eq!(df.column("CLOSE").unwrap().f64().unwrap().get(2), Some(20f64));
+        assert_eq!(df.column("VOLUME").unwrap().null_count(), 2);
+        assert_eq!(df.column("CLOSE").unwrap().null_count(), 1);
+        assert_eq!(df.column("RIC").unwrap().utf8().unwrap().get(2), Some("GME"));
+    }
+
+    #[test]
+    fn test_interval_from_str() {
+        assert_eq!("Weekly".parse::<Interval>().unwrap(), Interval::Weekly);
+        assert_eq!("PT5M".parse::<Interval>().unwrap(), Interval::FiveMinutes);
+        assert_eq!("taq".parse::<Interval>().unwrap(), Interval::Taq);
+        for i in [Interval::Tick, Interval::ThirtyMinutes, Interval::Hour, Interval::Quarterly] {
+            assert_eq!(i.to_string().parse::<Interval>().unwrap(), i);
+        }
+        assert!(matches!("weekley".parse::<Interval>(), Err(EkError::UnknownFrequency(_))));
+    }
+
+    #[test]
+    fn test_create_interval() {
+        let start = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap().and_hms_opt(9, 0, 0).unwrap();
+        let end = start + Duration::minutes(100);
+        let windows = create_interval(3, start, end, Interval::ThirtyMinutes.bar());
+        assert_eq!(windows, vec![
+            (start, start + Duration::minutes(30)),
+            (start + Duration::minutes(30), start + Duration::minutes(60)),
+            (start + Duration::minutes(60), end),
+        ]);
+
+        let windows = create_interval(3, start, end, None);
+        assert_eq!(windows.len(), 3);
+        assert_eq!(windows[2].1, end);
+    }
+
+    #[test]
+    fn test_groups() {
+        let start = NaiveDate::from_ymd_opt(2010, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let end = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let rics = (0..10).map(|i| format!("RIC{}", i)).collect::<Vec<String>>();
+
+        let payloads = groups(rics.to_owned(), &[TsField::Close], start, end, Interval::Daily, &Weekdays);
+        // 13 years of weekdays for 10 RICs are 33920 rows, so 12 chunks of at most 3000 rows
+        assert_eq!(payloads.len(), 12);
+        let windows = payloads.iter()
+            .map(|p| (serde_json::from_value::<NaiveDateTime>(p["startdate"].to_owned()).unwrap(),
+                      serde_json::from_value::<NaiveDateTime>(p["enddate"].to_owned()).unwrap()))
+            .collect::<Vec<(NaiveDateTime, NaiveDateTime)>>();
+        assert_eq!(windows[0].0, start);
+        assert_eq!(windows[11].1, end);
+        assert_eq!(windows[0].1 + Duration::seconds(1), windows[1].0);
+        for (s, e) in windows {
+            assert!(Weekdays.trading_days(s.date(), e.date()) * 10 < MAX_ROWS);
+        }
+
+        // 300 RICs fill a window of 9 weekdays, 2700 rows, as 10 weekdays would hit the limit
+        let many = (0..300).map(|i| format!("RIC{}", i)).collect::<Vec<String>>();
+        let monday = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let payloads = groups(many, &[TsField::Close], monday, monday + Duration::days(28), Interval::Daily, &Weekdays);
+        let first = serde_json::from_value::<NaiveDateTime>(payloads[0]["enddate"].to_owned()).unwrap();
+        assert_eq!(Weekdays.trading_days(monday.date(), first.date()), 9);
+
+        // Minute bars around the clock: 1440 rows a day leave room for two RICs per request
+        let end = start + Duration::days(7);
+        let payloads = groups(rics, &[TsField::Close], start, end, Interval::Minute, &Weekdays);
+        assert_eq!(payloads[0]["rics"].as_array().unwrap().len(), 2);
+        assert_eq!(payloads.len(), 5 * 5);
+
+        // A day cut in parts does not request its boundary bars twice
+        let rics = ["A", "B", "C"];
+        let start = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let end = start + Duration::days(2);
+        let parts = super::windows(start, end, Interval::Minute, &Weekdays, rics.len());
+        assert_eq!(parts[0], (start, start + Duration::hours(12) - Duration::seconds(1)));
+        for pair in parts.windows(2) {
+            assert_eq!(pair[0].1 + Duration::seconds(1), pair[1].0);
+        }
+        assert_eq!(parts.last().unwrap().1, end);
+
+        // Two weeks of ticks for one RIC are a few requests, not one per 3000 seconds of session
+        let payloads = groups(vec!["XOM".to_string()], &[TsField::All], start, start + Duration::days(14), Interval::Tick, &Weekdays);
+        assert_eq!(payloads.len(), 5);
+    }
+
+    #[test]
+    fn test_split_payload() {
+        let start = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let payload = assemble_payload(vec!["XOM".to_string(), "GME".to_string()], &[TsField::Close], "minute", &start, &(start + Duration::hours(2)));
+        let (a, b) = split_payload(&payload, Interval::Minute).unwrap();
+        assert_eq!(a["rics"], json!(["XOM"]));
+        assert_eq!(b["rics"], json!(["GME"]));
+
+        let (a, b) = split_payload(&a, Interval::Minute).unwrap();
+        assert_eq!(a["enddate"], json!(start + Duration::minutes(60) - Duration::seconds(1)));
+        assert_eq!(b["startdate"], json!(start + Duration::minutes(60)));
 
+        let payload = assemble_payload(vec!["XOM".to_string()], &[TsField::Close], "minute", &start, &(start + Duration::minutes(1)));
+        assert!(split_payload(&payload, Interval::Minute).is_none());
+
+        let rows = (0..MAX_ROWS).map(|_| json!(["2023-01-02T00:00:00Z", 1])).collect::<Vec<Value>>();
+        assert!(truncated(&json!({"timeseriesData": [{"ric": "XOM", "dataPoints": rows}]})));
+        assert!(!truncated(&json!({"timeseriesData": [{"ric": "XOM", "dataPoints": []}]})));
+    }
+
+    #[test]
+    fn test_follow_ups() {
+        let start = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let end = start + Duration::days(3);
+        let payload = assemble_payload(vec!["XOM".to_string(), "GME".to_string(), "IBM".to_string()], &[TsField::Close], "minute", &start, &end);
+        let fields = json!([{"name": "TIMESTAMP", "type": "DateTime"}, {"name": "CLOSE", "type": "Double"}]);
+        let res = json!({"timeseriesData": [
+            // Cut at the end
+            {"ric": "XOM", "statusCode": "Normal", "fields": fields, "dataPoints": [["2023-01-02T00:00:00Z", 1], ["2023-01-03T10:00:00Z", 2]]},
+            // Cut at the start
+            {"ric": "GME", "statusCode": "Normal", "fields": fields, "dataPoints": [["2023-01-04T12:00:00Z", 1], ["2023-01-05T00:00:00Z", 2]]},
+            {"ric": "IBM", "statusCode": "Normal", "fields": fields, "dataPoints": []}
+        ]});
+        let (before, after) = follow_ups(&payload, &res, Interval::Minute, &Weekdays).unwrap();
+
+        assert_eq!(before.len(), 1);
+        assert_eq!(before[0]["rics"], json!(["GME"]));
+        assert_eq!(before[0]["startdate"], json!(start));
+        assert_eq!(before[0]["enddate"], json!(NaiveDate::from_ymd_opt(2023, 1, 4).unwrap().and_hms_opt(11, 59, 59).unwrap()));
+
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0]["rics"], json!(["XOM"]));
+        assert_eq!(after[0]["startdate"], json!(NaiveDate::from_ymd_opt(2023, 1, 3).unwrap().and_hms_opt(10, 0, 1).unwrap()));
+        assert_eq!(after[0]["enddate"], json!(end));
+        assert_eq!(after[1]["rics"], json!(["IBM"]));
+        assert_eq!(after[1]["startdate"], json!(start));
+
+        let res = json!({"timeseriesData": [{"ric": "XOM", "statusCode": "Normal", "fields": [{"name": "CLOSE"}], "dataPoints": [[1]]}]});
+        assert!(follow_ups(&payload, &res, Interval::Minute, &Weekdays).is_none());
+
+        // A full daily chunk ending on a weekend: only the RIC missing Friday is requested again
+        let end = NaiveDate::from_ymd_opt(2023, 1, 15).unwrap().and_hms_opt(23, 59, 59).unwrap();
+        let payload = assemble_payload(vec!["XOM".to_string(), "GME".to_string()], &[TsField::Close], "daily", &start, &end);
+        let res = json!({"timeseriesData": [
+            {"ric": "XOM", "statusCode": "Normal", "fields": fields, "dataPoints": [["2023-01-02T00:00:00Z", 1], ["2023-01-13T00:00:00Z", 2]]},
+            {"ric": "GME", "statusCode": "Normal", "fields": fields, "dataPoints": [["2023-01-02T00:00:00Z", 1], ["2023-01-12T00:00:00Z", 2]]}
+        ]});
+        let (before, after) = follow_ups(&payload, &res, Interval::Daily, &Weekdays).unwrap();
+        assert!(before.is_empty());
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0]["rics"], json!(["GME"]));
+
+        // A busy RIC returning its first hour of ticks has the rest of the day split at that rate
+        let end = start + Duration::days(1);
+        let payload = assemble_payload(vec!["XOM".to_string()], &[TsField::Close], "tick", &start, &end);
+        let rows = (0..MAX_ROWS)
+            .map(|i| json!([format!("{}Z", (start + Duration::milliseconds(i as i64 * 1200)).format("%Y-%m-%dT%H:%M:%S%.3f")), 1]))
+            .collect::<Vec<Value>>();
+        let last = start + Duration::milliseconds((MAX_ROWS as i64 - 1) * 1200);
+        let res = json!({"timeseriesData": [{"ric": "XOM", "statusCode": "Normal", "fields": fields, "dataPoints": rows}]});
+        let (before, after) = follow_ups(&payload, &res, Interval::Tick, &Weekdays).unwrap();
+        assert!(before.is_empty());
+        assert_eq!(after.len(), 24);
+        assert_eq!(after[0]["startdate"], json!(last + Duration::milliseconds(1)));
+        for pair in after.windows(2) {
+            let e = serde_json::from_value::<NaiveDateTime>(pair[0]["enddate"].to_owned()).unwrap();
+            assert_eq!(json!(e + Duration::milliseconds(1)), pair[1]["startdate"]);
+        }
+        assert_eq!(after[23]["enddate"], json!(end));
+    }
+
+    #[test]
+    fn test_check_window() {
+        let now = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        assert!(check_window(Interval::Tick, now - Duration::days(30), now, now).is_ok());
+        assert!(check_window(Interval::Daily, now - Duration::days(3650), now, now).is_ok());
+        match check_window(Interval::Tick, now - Duration::days(120), now, now) {
+            Err(EkError::DateError(e)) => assert!(e.starts_with("tick data is only kept for 90 days")),
+            r => panic!("Expected a date error, got {:?}", r),
+        }
+        assert!(check_window(Interval::FiveMinutes, now - Duration::days(400), now, now).is_err());
+        assert!(check_window(Interval::Daily, now, now, now).is_err());
+    }
+
+    #[test]
+    fn test_check_fields() {
+        let res = vec![json!({"timeseriesData": [
+            {"ric": "XOM", "statusCode": "Normal", "fields": [{"name": "TIMESTAMP"}, {"name": "CLOSE"}, {"name": "VOLUME"}]},
+            {"ric": "US10YT=RR", "statusCode": "Normal", "fields": [{"name": "TIMESTAMP"}, {"name": "CLOSE"}]},
+            {"ric": "NOPE", "statusCode": "Error"}
+        ]})];
+        let requested = vec![TsField::Timestamp, TsField::Close, TsField::Volume, TsField::from("open")];
+        assert_eq!(check_fields(&requested, &res), vec![
+            MissingFields { ric: "XOM".to_string(), fields: vec!["OPEN".to_string()] },
+            MissingFields { ric: "US10YT=RR".to_string(), fields: vec!["VOLUME".to_string(), "OPEN".to_string()] },
+        ]);
+        assert!(check_fields(&[TsField::All], &res).is_empty());
+        assert_eq!(TsField::from("BID"), TsField::Other("BID".to_string()));
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::fmt;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
 use polars::prelude::*;
 use serde_json::{Value, json};
 
@@ -7,21 +8,28 @@ pub fn clean_string(s: String) -> String {
     s.replace("\"", "")
 }
 
-pub enum EkResults {
-    DF(DataFrame),
-    Raw(Vec<Value>),
-    Err(EkError),
-}
-
+#[derive(Debug)]
 pub enum EkError {
     NoData(String),
     NoHeaders(String),
     NoDataFrame(String),
     AuthError(String),
-    ConnectionError(String),
+    ServerError(i64, String),
     ThreadError(String),
     DateError(String),
-    Error(String),
+    PortDiscovery(Vec<u16>),
+    TicketExpired(String),
+    RateLimited(String),
+    CellError(i64, String),
+    UnknownFrequency(String),
+    Http(reqwest::Error),
+    Json(serde_json::Error),
+    Polars(PolarsError),
+    DateParse(chrono::ParseError),
+    Io(std::io::Error),
+    Config(String),
+    Parquet(parquet::errors::ParquetError),
+    Arrow(arrow_schema::ArrowError),
 }
 
 impl fmt::Display for EkError {
@@ -31,14 +39,87 @@ impl fmt::Display for EkError {
             EkError::NoHeaders(e) => write!(f, "No headers returned: {}", e),
             EkError::NoDataFrame(e) => write!(f, "No dataframe returned: {}", e),
             EkError::AuthError(e) => write!(f, "Authentication error: {}", e),
-            EkError::ConnectionError(e) => write!(f, "Connection error: {}", e),
+            EkError::ServerError(code, e) => write!(f, "Server error {}: {}", code, e),
             EkError::ThreadError(e) => write!(f, "Thread error: {}", e),
             EkError::DateError(e) => write!(f, "Date error: {}", e),
-            EkError::Error(e) => write!(f, "Error: {}", e)
+            EkError::PortDiscovery(ports) => {
+                let tried = ports.iter().map(|p| p.to_string()).collect::<Vec<String>>();
+                write!(f, "Could not find a ready Eikon proxy, tried ports: {}", tried.join(", "))
+            }
+            EkError::TicketExpired(t) => write!(f, "Ticket {} expired before the data was ready", t),
+            EkError::RateLimited(e) => write!(f, "Rate limited: {}", e),
+            EkError::CellError(code, e) => write!(f, "Cell error {}: {}", code, e),
+            EkError::UnknownFrequency(e) => write!(f, "Unknown frequency or interval: {}", e),
+            EkError::Http(e) if e.is_timeout() => write!(f, "Request timed out: {}", e),
+            EkError::Http(e) => write!(f, "HTTP error: {}", e),
+            EkError::Json(e) => write!(f, "Invalid JSON: {}", e),
+            EkError::Polars(e) => write!(f, "Polars error: {}", e),
+            EkError::DateParse(e) => write!(f, "Could not parse date: {}", e),
+            EkError::Io(e) => write!(f, "IO error: {}", e),
+            EkError::Config(e) => write!(f, "Configuration error: {}", e),
+            EkError::Parquet(e) => write!(f, "Parquet error: {}", e),
+            EkError::Arrow(e) => write!(f, "Arrow error: {}", e),
         }
     }
 }
 
+impl std::error::Error for EkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EkError::Http(e) => Some(e),
+            EkError::Json(e) => Some(e),
+            EkError::Polars(e) => Some(e),
+            EkError::DateParse(e) => Some(e),
+            EkError::Io(e) => Some(e),
+            EkError::Parquet(e) => Some(e),
+            EkError::Arrow(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<reqwest::Error> for EkError {
+    fn from(e: reqwest::Error) -> Self {
+        EkError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for EkError {
+    fn from(e: serde_json::Error) -> Self {
+        EkError::Json(e)
+    }
+}
+
+impl From<PolarsError> for EkError {
+    fn from(e: PolarsError) -> Self {
+        EkError::Polars(e)
+    }
+}
+
+impl From<chrono::ParseError> for EkError {
+    fn from(e: chrono::ParseError) -> Self {
+        EkError::DateParse(e)
+    }
+}
+
+impl From<std::io::Error> for EkError {
+    fn from(e: std::io::Error) -> Self {
+        EkError::Io(e)
+    }
+}
+
+impl From<parquet::errors::ParquetError> for EkError {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        EkError::Parquet(e)
+    }
+}
+
+impl From<arrow_schema::ArrowError> for EkError {
+    fn from(e: arrow_schema::ArrowError) -> Self {
+        EkError::Arrow(e)
+    }
+}
+
 pub enum Fields {
     Params(HashMap<String, HashMap<String, String>>),
     NoParams(Vec<String>),
@@ -55,6 +136,8 @@ pub fn field_builder(fields: Fields) -> Value {
         }
         Fields::Params(fields) => {
             let mut res = Vec::with_capacity(fields.len());
+            let mut fields = fields.into_iter().collect::<Vec<(String, HashMap<String, String>)>>();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
             for (k, v) in fields.iter() {
                 res.push(json!({"name": k, "parameters": v}));
             }
@@ -64,34 +147,116 @@ pub fn field_builder(fields: Fields) -> Value {
     json!(res)
 }
 
-fn missing_in_vec<'a>(v1: Vec<&'a str>, v2: &Vec<&'a str>) -> Vec<&'a str> {
-    let mut missing = Vec::new();
-    for i in v1.into_iter() {
-        if !v2.contains(&i) {
-            missing.push(i);
+/// Parses the timestamps sent by the server, e.g. `2023-01-03T00:00:00Z` or `2023-01-03T14:30:00.123Z`.
+pub fn parse_timestamp(s: &str) -> Option<NaiveDateTime> {
+    if let Ok(r) = DateTime::parse_from_rfc3339(s) {
+        return Some(r.naive_utc());
+    }
+    if let Ok(r) = NaiveDateTime::parse_from_str(s.trim_end_matches('Z'), "%Y-%m-%dT%H:%M:%S%.f") {
+        return Some(r);
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0))
+}
+
+/// Escapes the characters that cannot appear in a file name, e.g. `EUR/USD` becomes `EUR%2FUSD`.
+pub fn file_name(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '%' => format!("%{:02X}", c as u32),
+            c => c.to_string()
+        })
+        .collect()
+}
+
+/// Field code with its parameters, e.g. `TR.CLOSE(Curn=EUR,Scale=6)`, as used by the platform and in
+/// column names.
+pub fn field_string(field: &Value) -> String {
+    let name = clean_string(field["name"].to_string());
+    match field["parameters"].as_object() {
+        None => name,
+        Some(p) if p.is_empty() => name,
+        Some(p) => {
+            let mut params = p.iter()
+                .map(|(k, v)| format!("{}={}", k, clean_string(v.to_string())))
+                .collect::<Vec<String>>();
+            params.sort();
+            format!("{}({})", name, params.join(","))
         }
     }
-    missing
 }
 
-fn create_series_String(header: &str, l: usize) -> Series {
-    let value: Option<String> = None;
-    let series = Series::new(header, vec![value; l]);
-    series
+/// Reads a field written as by `field_string`, e.g. `TR.CLOSE(Curn=EUR,Scale=6)`, into the form
+/// `field_builder` produces.
+pub fn parse_field(s: &str) -> Result<Value, EkError> {
+    let s = s.trim();
+    let (name, params) = match s.split_once('(') {
+        None => return Ok(json!({"name": s})),
+        Some((name, rest)) => match rest.strip_suffix(')') {
+            None => return Err(EkError::Config(format!("Unclosed parameters in field {}", s))),
+            Some(p) => (name.trim(), p)
+        }
+    };
+    let mut parameters = serde_json::Map::new();
+    for param in params.split(',').filter(|p| !p.trim().is_empty()) {
+        match param.split_once('=') {
+            None => return Err(EkError::Config(format!("Parameter {} of field {} is not KEY=VALUE", param, name))),
+            Some((k, v)) => parameters.insert(k.trim().to_string(), json!(v.trim())),
+        };
+    }
+    if parameters.is_empty() {
+        return Ok(json!({"name": name}));
+    }
+    Ok(json!({"name": name, "parameters": parameters}))
 }
 
-pub fn vstack_diag(mut long: DataFrame, mut short: DataFrame) -> DataFrame {
-    let long_col = long.get_column_names();
-    let short_col = short.get_column_names();
-    let missing = missing_in_vec(long_col, &short_col)
-        .into_iter()
-        .map(|x| create_series_String(x, short.shape().0))
-        .collect::<Vec<Series>>();
+/// Type both sides of a column are cast to when stacking frames that disagree on it.
+fn common_dtype(a: &DataType, b: &DataType) -> DataType {
+    if a == b {
+        a.to_owned()
+    } else if *a == DataType::Null {
+        b.to_owned()
+    } else if *b == DataType::Null {
+        a.to_owned()
+    } else if a.is_numeric() && b.is_numeric() {
+        DataType::Float64
+    } else {
+        DataType::Utf8
+    }
+}
 
-    let short = short.with_column(missing[0].to_owned()).unwrap().to_owned();
-    let long_col = long.get_column_names();
-    let short = short.select(long_col).unwrap();
-    long.vstack(&short).unwrap()
+/// Stacks `bottom` under `top` when they do not have the same columns.
+///
+/// Columns missing on either side are filled with nulls of the type the other side has, a column
+/// whose types differ is cast to a type that holds both.
+///
+/// # Returns
+///
+/// A frame with the columns of `top` in their order, followed by the columns only `bottom` has
+pub fn vstack_diag(top: DataFrame, bottom: DataFrame) -> Result<DataFrame, EkError> {
+    let mut names: Vec<String> = top.get_column_names_owned();
+    for name in bottom.get_column_names() {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+
+    let align = |df: &DataFrame, other: &DataFrame| -> Result<DataFrame, EkError> {
+        let mut columns = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            let column = match (df.column(name), other.column(name)) {
+                (Ok(c), Ok(o)) => c.cast(&common_dtype(c.dtype(), o.dtype()))?,
+                (Ok(c), Err(_)) => c.to_owned(),
+                (Err(_), Ok(o)) => Series::full_null(name, df.height(), o.dtype()),
+                (Err(e), Err(_)) => return Err(e.into()),
+            };
+            columns.push(column);
+        }
+        Ok(DataFrame::new(columns)?)
+    };
+
+    let mut res = align(&top, &bottom)?;
+    res.vstack_mut(&align(&bottom, &top)?)?;
+    Ok(res)
 }
 
 
@@ -99,6 +264,41 @@ pub fn vstack_diag(mut long: DataFrame, mut short: DataFrame) -> DataFrame {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_vstack_diag() {
+        let top = df!("TIMESTAMP" => &["2023-01-02", "2023-01-03"], "CLOSE" => &[1i64, 2], "RIC" => &["XOM", "XOM"]).unwrap();
+        let bottom = df!("TIMESTAMP" => &["2023-01-02"], "YIELD" => &[4.5], "CLOSE" => &[1.5], "RIC" => &["US10YT=RR"]).unwrap();
+
+        let res = vstack_diag(top.to_owned(), bottom.to_owned()).unwrap();
+        assert_eq!(res.get_column_names(), vec!["TIMESTAMP", "CLOSE", "RIC", "YIELD"]);
+        assert_eq!(res.column("CLOSE").unwrap().dtype(), &DataType::Float64);
+        assert_eq!(res.column("YIELD").unwrap().dtype(), &DataType::Float64);
+        assert_eq!(res.column("YIELD").unwrap().null_count(), 2);
+        assert_eq!(res.column("RIC").unwrap().utf8().unwrap().get(2), Some("US10YT=RR"));
+
+        // Same columns, nothing missing on either side
+        let res = vstack_diag(top.to_owned(), top.to_owned()).unwrap();
+        assert_eq!(res.shape(), (4, 3));
+        assert_eq!(res.column("CLOSE").unwrap().dtype(), &DataType::Int64);
+
+        // Text and numbers in the same column end up as text
+        let other = df!("CLOSE" => &["n/a"]).unwrap();
+        let res = vstack_diag(top, other).unwrap();
+        assert_eq!(res.column("CLOSE").unwrap().dtype(), &DataType::Utf8);
+        assert_eq!(res.column("TIMESTAMP").unwrap().null_count(), 1);
+    }
+
+    #[test]
+    fn test_parse_field() {
+        let field = parse_field("TR.CLOSE(Curn=EUR, Scale=6)").unwrap();
+        assert_eq!(field, json!({"name": "TR.CLOSE", "parameters": {"Curn": "EUR", "Scale": "6"}}));
+        assert_eq!(field_string(&field), "TR.CLOSE(Curn=EUR,Scale=6)");
+        assert_eq!(parse_field("TR.VOLUME").unwrap(), json!({"name": "TR.VOLUME"}));
+        assert_eq!(parse_field("TR.VOLUME()").unwrap(), json!({"name": "TR.VOLUME"}));
+        assert!(parse_field("TR.CLOSE(Curn=EUR").is_err());
+        assert!(parse_field("TR.CLOSE(Curn)").is_err());
+    }
+
     #[test]
     fn test_clean_string() {
         let s = String::from("\"hello\"");
@@ -125,7 +325,7 @@ mod tests {
         let mut param = HashMap::new();
         param.insert("Curn".to_string(), "EUR".to_string());
         field.insert("TR.CLOSE".to_string(), param);
-        let answer: Value = json!([{"name": "TR.GrossProfit", "parameters": {"Scale": "6", "Curn": "EUR"}}, {"name": "TR.CLOSE", "parameters": {"Curn": "EUR"}}]);
+        let answer: Value = json!([{"name": "TR.CLOSE", "parameters": {"Curn": "EUR"}}, {"name": "TR.GrossProfit", "parameters": {"Scale": "6", "Curn": "EUR"}}]);
         let res = field_builder(Fields::Params(field));
         assert_eq!(res, answer);
 
@@ -133,6 +333,33 @@ mod tests {
         let answer: Value = json!([{"name": "TR.GrossProfit"}, {"name": "TR.CLOSE"}]);
         let res = field_builder(Fields::NoParams(fields));
         assert_eq!(res, answer);
+
+        assert_eq!(field_string(&json!({"name": "TR.CLOSE", "parameters": {"Curn": "EUR", "Scale": "6"}})), "TR.CLOSE(Curn=EUR,Scale=6)");
+        assert_eq!(field_string(&json!({"name": "TR.CLOSE"})), "TR.CLOSE");
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let day = NaiveDate::from_ymd_opt(2023, 1, 3).unwrap();
+        assert_eq!(parse_timestamp("2023-01-03T14:30:00.500Z"), day.and_hms_milli_opt(14, 30, 0, 500));
+        assert_eq!(parse_timestamp("2023-01-03T14:30:00"), day.and_hms_opt(14, 30, 0));
+        assert_eq!(parse_timestamp("2023-01-03"), day.and_hms_opt(0, 0, 0));
+        assert_eq!(parse_timestamp("XOM"), None);
+    }
+
+    #[test]
+    fn test_error_source() {
+        use std::error::Error;
+
+        let e: EkError = serde_json::from_str::<Value>("{").unwrap_err().into();
+        assert!(matches!(e, EkError::Json(_)));
+        assert!(e.source().is_some());
+
+        let e: EkError = chrono::NaiveDate::parse_from_str("2023-13-01", "%Y-%m-%d").unwrap_err().into();
+        assert!(e.to_string().starts_with("Could not parse date"));
+        assert!(e.source().is_some());
+
+        assert!(EkError::NoData("empty".to_string()).source().is_none());
     }
 }
 